@@ -0,0 +1,71 @@
+//! Patterns that match one account, or all subaccounts of a parent account,
+//! such as the tokens created by a factory
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use serde::{Deserialize, Serialize};
+
+/// An account id, or all subaccounts of an account written as `*.parent.near`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum AccountPattern {
+    Exact(AccountId),
+    /// Accounts ending with this suffix, including the leading `.`
+    Suffix(String),
+}
+
+impl AccountPattern {
+    pub fn matches(&self, account_id: &AccountId) -> bool {
+        match self {
+            AccountPattern::Exact(exact) => exact == account_id,
+            AccountPattern::Suffix(suffix) => account_id.as_str().ends_with(suffix.as_str()),
+        }
+    }
+}
+
+impl FromStr for AccountPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('*') {
+            Some(suffix) => {
+                let parent = suffix
+                    .strip_prefix('.')
+                    .ok_or_else(|| format!("Expected `*.` at the start of {s}"))?;
+                parent
+                    .parse::<AccountId>()
+                    .map_err(|e| format!("Invalid account pattern {s}: {e}"))?;
+                Ok(AccountPattern::Suffix(suffix.to_owned()))
+            }
+            None => s
+                .parse()
+                .map(AccountPattern::Exact)
+                .map_err(|e| format!("Invalid account id {s}: {e}")),
+        }
+    }
+}
+
+impl TryFrom<String> for AccountPattern {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<AccountPattern> for String {
+    fn from(pattern: AccountPattern) -> Self {
+        pattern.to_string()
+    }
+}
+
+impl Display for AccountPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountPattern::Exact(account_id) => write!(f, "{account_id}"),
+            AccountPattern::Suffix(suffix) => write!(f, "*{suffix}"),
+        }
+    }
+}
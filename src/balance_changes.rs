@@ -0,0 +1,183 @@
+//! Exact native NEAR balance changes, read from the state changes of a block
+//! instead of being inferred from actions.
+
+use std::collections::{BTreeMap, HashMap};
+
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_indexer_primitives::views::{
+    StateChangeCauseView, StateChangeValueView, StateChangeWithCauseView,
+};
+use inindexer::near_indexer_primitives::StreamerMessage;
+use serde::Serialize;
+
+use crate::serde_utils::{dec_format, option_dec_format};
+
+/// One update of an account's balance. Gas purchases and refunds, validator
+/// rewards, storage staking and refunds of failed receipts are all included.
+#[derive(Clone, Debug, Serialize)]
+pub struct NearBalanceChange {
+    pub account_id: AccountId,
+    /// `None` if the balance is not known, which is always the case for the
+    /// first change of each account after the indexer started, and after the
+    /// account was created. It's also `None` if the account's last balance
+    /// was forgotten, see [`NearBalanceChanges::with_capacity`]. The previous
+    /// balance is then the balance at the end of the previous block, which is
+    /// not in the block's state changes.
+    #[serde(with = "option_dec_format")]
+    pub balance_before: Option<u128>,
+    #[serde(with = "dec_format")]
+    pub balance_after: u128,
+    #[serde(with = "option_dec_format")]
+    pub locked_before: Option<u128>,
+    #[serde(with = "dec_format")]
+    pub locked_after: u128,
+    /// Set if the account was deleted, balances after are 0 in this case
+    pub deleted: bool,
+    pub cause: StateChangeCauseView,
+    pub block_height: BlockHeight,
+    #[serde(with = "dec_format")]
+    pub block_timestamp_nanosec: u128,
+    /// Position of the change among all balance changes of the block
+    pub index_in_block: usize,
+}
+
+impl NearBalanceChange {
+    pub fn event_id(&self) -> String {
+        format!("{}-balance{}", self.block_height, self.index_in_block)
+    }
+}
+
+/// Finds the balance changes of consecutive blocks. The last balance of
+/// recently changed accounts is kept, so the balance before their first
+/// change in a block is known if it already changed in an earlier block that
+/// was processed.
+pub struct NearBalanceChanges {
+    capacity: usize,
+    /// Balance and locked balance after the last change of each account, and
+    /// when it changed. Deleted accounts are removed.
+    last_balances: HashMap<AccountId, LastBalance>,
+    /// Accounts in `last_balances` by when their balance last changed
+    by_last_change: BTreeMap<u64, AccountId>,
+    change_counter: u64,
+}
+
+struct LastBalance {
+    balance: u128,
+    locked: u128,
+    changed_at: u64,
+}
+
+impl Default for NearBalanceChanges {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NearBalanceChanges {
+    /// How many last balances are kept by [`new`](Self::new)
+    pub const DEFAULT_CAPACITY: usize = 1_000_000;
+
+    pub fn new() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+
+    /// Keeps the last balances of up to `capacity` accounts. When there are
+    /// more, the balances of the accounts that changed least recently are
+    /// forgotten.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            last_balances: HashMap::new(),
+            by_last_change: BTreeMap::new(),
+            change_counter: 0,
+        }
+    }
+
+    /// Forgets the last balance of the account and returns it
+    fn remove(&mut self, account_id: &AccountId) -> Option<(u128, u128)> {
+        let last = self.last_balances.remove(account_id)?;
+        self.by_last_change.remove(&last.changed_at);
+        Some((last.balance, last.locked))
+    }
+
+    /// Replaces the last balance of the account and returns the previous one
+    fn update(
+        &mut self,
+        account_id: &AccountId,
+        balance: u128,
+        locked: u128,
+    ) -> Option<(u128, u128)> {
+        let previous = self.remove(account_id);
+        let changed_at = self.change_counter;
+        self.change_counter += 1;
+        self.last_balances.insert(
+            account_id.clone(),
+            LastBalance {
+                balance,
+                locked,
+                changed_at,
+            },
+        );
+        self.by_last_change.insert(changed_at, account_id.clone());
+        if self.last_balances.len() > self.capacity {
+            if let Some((_, oldest)) = self.by_last_change.pop_first() {
+                self.last_balances.remove(&oldest);
+            }
+        }
+        previous
+    }
+
+    /// Balance changes of all accounts in the block, in the order they were
+    /// applied
+    pub fn get_balance_changes(&mut self, block: &StreamerMessage) -> Vec<NearBalanceChange> {
+        self.process_state_changes(
+            block.block.header.height,
+            block.block.header.timestamp_nanosec.into(),
+            block.shards.iter().flat_map(|shard| &shard.state_changes),
+        )
+    }
+
+    /// Balance changes in the state changes of a block, which must be
+    /// processed in order
+    pub fn process_state_changes<'a>(
+        &mut self,
+        block_height: BlockHeight,
+        block_timestamp_nanosec: u128,
+        state_changes: impl IntoIterator<Item = &'a StateChangeWithCauseView>,
+    ) -> Vec<NearBalanceChange> {
+        let mut changes: Vec<NearBalanceChange> = Vec::new();
+        for change in state_changes {
+            let (account_id, balance_after, locked_after, deleted) = match &change.value {
+                StateChangeValueView::AccountUpdate {
+                    account_id,
+                    account,
+                } => (account_id, account.amount, account.locked, false),
+                StateChangeValueView::AccountDeletion { account_id } => (account_id, 0, 0, true),
+                _ => continue,
+            };
+            let previous = if deleted {
+                self.remove(account_id)
+            } else {
+                self.update(account_id, balance_after, locked_after)
+            };
+            let balance_before = previous.map(|(balance, _)| balance);
+            let locked_before = previous.map(|(_, locked)| locked);
+            if balance_before == Some(balance_after) && locked_before == Some(locked_after) {
+                continue;
+            }
+            changes.push(NearBalanceChange {
+                account_id: account_id.clone(),
+                balance_before,
+                balance_after,
+                locked_before,
+                locked_after,
+                deleted,
+                cause: change.cause.clone(),
+                block_height,
+                block_timestamp_nanosec,
+                index_in_block: changes.len(),
+            });
+        }
+        changes
+    }
+}
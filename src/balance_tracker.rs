@@ -0,0 +1,388 @@
+//! Balances of every account in every token, folded from the indexed events
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_utils::{FtBurnEvent, FtMintEvent, FtTransferEvent};
+use serde::{Deserialize, Serialize};
+
+use crate::balance_changes::NearBalanceChange;
+use crate::serde_utils::signed_dec_format;
+use crate::snapshot::{is_processed, Snapshot, SnapshotSchedule};
+use crate::{EventContext, FtEventHandler, FtHandlerError};
+
+/// Token id of native NEAR balances, which are taken from the state changes
+/// of each block
+pub const NEAR_TOKEN_ID: &str = "near";
+
+/// Change of one balance in a block
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BalanceChange {
+    pub token_id: AccountId,
+    pub account_id: AccountId,
+    #[serde(with = "signed_dec_format")]
+    pub delta: i128,
+    /// Balance after the block
+    #[serde(with = "signed_dec_format")]
+    pub balance: i128,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    token_id: AccountId,
+    account_id: AccountId,
+    #[serde(with = "signed_dec_format")]
+    balance: i128,
+}
+
+#[derive(Default)]
+struct BalanceState {
+    /// Last block whose events are included in the balances
+    block_height: Option<BlockHeight>,
+    /// Token id -> account id -> balance. Zero balances are removed.
+    balances: HashMap<AccountId, HashMap<AccountId, i128>>,
+    last_block_changes: Vec<BalanceChange>,
+}
+
+impl BalanceState {
+    fn balance(&self, token_id: &AccountId, account_id: &AccountId) -> i128 {
+        self.balances
+            .get(token_id)
+            .and_then(|holders| holders.get(account_id))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Read access to the balances of a [`BalanceTracker`], which can be used
+/// from other tasks while the indexer is running
+#[derive(Clone)]
+pub struct BalanceReader {
+    state: Arc<RwLock<BalanceState>>,
+}
+
+impl BalanceReader {
+    /// Last block whose events are included in the balances
+    pub fn block_height(&self) -> Option<BlockHeight> {
+        self.state.read().unwrap().block_height
+    }
+
+    pub fn balance(&self, token_id: &AccountId, account_id: &AccountId) -> i128 {
+        self.state.read().unwrap().balance(token_id, account_id)
+    }
+
+    /// Non-zero balances of an account in all tokens
+    pub fn balances_of(&self, account_id: &AccountId) -> HashMap<AccountId, i128> {
+        self.state
+            .read()
+            .unwrap()
+            .balances
+            .iter()
+            .filter_map(|(token_id, holders)| Some((token_id.clone(), *holders.get(account_id)?)))
+            .collect()
+    }
+
+    /// Non-zero balances of all accounts in a token
+    pub fn holders(&self, token_id: &AccountId) -> HashMap<AccountId, i128> {
+        self.state
+            .read()
+            .unwrap()
+            .balances
+            .get(token_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Non-zero balances of all accounts in all tokens as (token id, account
+    /// id, balance), sorted, with the block they are valid at
+    pub fn all_balances(&self) -> (Option<BlockHeight>, Vec<(AccountId, AccountId, i128)>) {
+        let state = self.state.read().unwrap();
+        let mut balances: Vec<_> = state
+            .balances
+            .iter()
+            .flat_map(|(token_id, holders)| {
+                holders
+                    .iter()
+                    .map(|(account_id, balance)| (token_id.clone(), account_id.clone(), *balance))
+            })
+            .collect();
+        balances.sort_unstable();
+        (state.block_height, balances)
+    }
+
+    /// Up to `limit` non-zero balances as (token id, account id, balance) that
+    /// come after `after` in that order, with the block they are valid at.
+    /// Tokens for which `include_token` returns `false` are skipped. Only the
+    /// balances of one token at a time are sorted, so it's cheaper than
+    /// [`all_balances`](Self::all_balances) for going through them in pages.
+    pub fn balances_after(
+        &self,
+        after: Option<(&AccountId, &AccountId)>,
+        limit: usize,
+        include_token: impl Fn(&AccountId) -> bool,
+    ) -> (Option<BlockHeight>, Vec<(AccountId, AccountId, i128)>) {
+        let state = self.state.read().unwrap();
+        let mut token_ids: Vec<_> = state
+            .balances
+            .keys()
+            .filter(|token_id| {
+                include_token(token_id)
+                    && match after {
+                        Some((after_token_id, _)) => *token_id >= after_token_id,
+                        None => true,
+                    }
+            })
+            .collect();
+        token_ids.sort_unstable();
+        let mut balances = Vec::new();
+        for token_id in token_ids {
+            if balances.len() >= limit {
+                break;
+            }
+            let mut holders: Vec<_> = state.balances[token_id]
+                .iter()
+                .filter(|(account_id, _)| match after {
+                    Some((after_token_id, after_account_id)) if token_id == after_token_id => {
+                        *account_id > after_account_id
+                    }
+                    _ => true,
+                })
+                .collect();
+            holders.sort_unstable();
+            balances.extend(
+                holders
+                    .into_iter()
+                    .take(limit - balances.len())
+                    .map(|(account_id, balance)| (token_id.clone(), account_id.clone(), *balance)),
+            );
+        }
+        (state.block_height, balances)
+    }
+
+    /// Balances that changed in [`block_height`](Self::block_height)
+    pub fn last_block_changes(&self) -> Vec<BalanceChange> {
+        self.state.read().unwrap().last_block_changes.clone()
+    }
+}
+
+/// Handler that keeps the balance of every account in every token.
+///
+/// Balances only include events indexed by this tracker, so they are negative
+/// if an account spent tokens that it received before the tracker was started.
+/// Native NEAR transfers are not counted, and neither are events with
+/// [`token_verified`](EventContext::token_verified) set to `false`. NEAR
+/// balances of every account that changed can be set from the state changes
+/// of each block instead, under [`NEAR_TOKEN_ID`], see
+/// [`with_near_balances`](Self::with_near_balances).
+/// Events of blocks that are already included in the balances, for example
+/// when blocks are processed again after a restore, are ignored.
+pub struct BalanceTracker {
+    state: Arc<RwLock<BalanceState>>,
+    /// Changes of the block that is being processed, applied on flush
+    pending: HashMap<(AccountId, AccountId), i128>,
+    track_near_balances: bool,
+    snapshots: SnapshotSchedule,
+}
+
+impl Default for BalanceTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BalanceTracker {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(RwLock::new(BalanceState::default())),
+            pending: HashMap::new(),
+            track_near_balances: false,
+            snapshots: SnapshotSchedule::default(),
+        }
+    }
+
+    /// Loads balances saved by [`snapshot`](Self::snapshot). Indexing should
+    /// continue from the block after [`BalanceReader::block_height`].
+    pub fn restore(path: impl AsRef<Path>) -> Result<Self, FtHandlerError> {
+        let snapshot = Snapshot::<SnapshotEntry>::load(path.as_ref())?;
+        let mut state = BalanceState {
+            block_height: snapshot.block_height,
+            ..Default::default()
+        };
+        for entry in snapshot.entries {
+            state
+                .balances
+                .entry(entry.token_id)
+                .or_default()
+                .insert(entry.account_id, entry.balance);
+        }
+        Ok(Self {
+            state: Arc::new(RwLock::new(state)),
+            snapshots: SnapshotSchedule::restored(snapshot.block_height),
+            ..Self::new()
+        })
+    }
+
+    /// Also keeps NEAR balances. This includes every account that is touched
+    /// on chain, which makes the balances and their snapshots much larger.
+    pub fn with_near_balances(mut self) -> Self {
+        self.track_near_balances = true;
+        self
+    }
+
+    /// Saves a snapshot to `path` after every `interval` blocks. Every
+    /// snapshot rewrites all balances, so use a larger interval for large
+    /// balance sets.
+    pub fn with_snapshots(mut self, path: impl Into<PathBuf>, interval: BlockHeight) -> Self {
+        self.snapshots.enable(path.into(), interval);
+        self
+    }
+
+    pub fn reader(&self) -> BalanceReader {
+        BalanceReader {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Writes all balances and the block they are valid at to `path`
+    pub fn snapshot(&self, path: impl AsRef<Path>) -> Result<(), FtHandlerError> {
+        let snapshot = {
+            let state = self.state.read().unwrap();
+            Snapshot {
+                block_height: state.block_height,
+                entries: state
+                    .balances
+                    .iter()
+                    .flat_map(|(token_id, holders)| {
+                        holders.iter().map(|(account_id, balance)| SnapshotEntry {
+                            token_id: token_id.clone(),
+                            account_id: account_id.clone(),
+                            balance: *balance,
+                        })
+                    })
+                    .collect(),
+            }
+        };
+        snapshot.save(path.as_ref())
+    }
+
+    fn is_processed(&self, block_height: BlockHeight) -> bool {
+        is_processed(self.state.read().unwrap().block_height, block_height)
+    }
+
+    fn add(&mut self, context: &EventContext, account_id: &AccountId, delta: i128) {
+        if context.native_transfer.is_some()
+            || context.token_verified == Some(false)
+            || self.is_processed(context.block_height)
+        {
+            return;
+        }
+        let pending = self
+            .pending
+            .entry((context.contract_id.clone(), account_id.clone()))
+            .or_default();
+        *pending = pending.saturating_add(delta);
+    }
+}
+
+/// Amounts above `i128::MAX` can't be real balances, they are capped
+fn signed(amount: u128) -> i128 {
+    i128::try_from(amount).unwrap_or(i128::MAX)
+}
+
+#[async_trait]
+impl FtEventHandler for BalanceTracker {
+    async fn handle_mint(
+        &mut self,
+        mint: FtMintEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.add(&context, &mint.owner_id, signed(mint.amount));
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: FtTransferEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.add(&context, &transfer.old_owner_id, -signed(transfer.amount));
+        self.add(&context, &transfer.new_owner_id, signed(transfer.amount));
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: FtBurnEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.add(&context, &burn.owner_id, -signed(burn.amount));
+        Ok(())
+    }
+
+    async fn handle_near_balance_change(
+        &mut self,
+        change: NearBalanceChange,
+    ) -> Result<(), FtHandlerError> {
+        if !self.track_near_balances || self.is_processed(change.block_height) {
+            return Ok(());
+        }
+        let token_id: AccountId = NEAR_TOKEN_ID.parse().unwrap();
+        let current = self
+            .state
+            .read()
+            .unwrap()
+            .balance(&token_id, &change.account_id);
+        let pending = self
+            .pending
+            .entry((token_id, change.account_id))
+            .or_default();
+        // The balance after the change is exact, so the delta is whatever
+        // brings the current balance to it
+        *pending = signed(change.balance_after).saturating_sub(current);
+        Ok(())
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), FtHandlerError> {
+        let pending = std::mem::take(&mut self.pending);
+        if self.is_processed(block_height) {
+            return Ok(());
+        }
+        {
+            let mut state = self.state.write().unwrap();
+            let mut changes = Vec::new();
+            for ((token_id, account_id), delta) in pending {
+                if delta == 0 {
+                    continue;
+                }
+                let holders = state.balances.entry(token_id.clone()).or_default();
+                let balance = holders
+                    .get(&account_id)
+                    .copied()
+                    .unwrap_or_default()
+                    .saturating_add(delta);
+                if balance == 0 {
+                    holders.remove(&account_id);
+                } else {
+                    holders.insert(account_id.clone(), balance);
+                }
+                changes.push(BalanceChange {
+                    token_id,
+                    account_id,
+                    delta,
+                    balance,
+                });
+            }
+            state.block_height = Some(block_height);
+            state.last_block_changes = changes;
+        }
+
+        if let Some(path) = self.snapshots.due(block_height) {
+            self.snapshot(path)?;
+            self.snapshots.saved(block_height);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,458 @@
+//! Handlers that wrap other handlers: [`Tee`] sends events to several
+//! handlers, [`Filter`] drops events, and [`Map`] changes or drops them.
+
+use inindexer::near_indexer_primitives::near_primitives::errors::TxExecutionError;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use inindexer::near_utils::{FtBurnEvent, FtMintEvent, FtTransferEvent};
+
+use crate::balance_changes::NearBalanceChange;
+use crate::discovery::{FtTokenDeployedEvent, FtTokenDiscoveredEvent};
+use crate::mt::{MtBurnEvent, MtMintEvent, MtTransferEvent};
+use crate::storage::FtStorageEvent;
+use crate::supply::SupplyChange;
+use crate::transfer_call::FtEffectiveTransferEvent;
+use crate::wrap::NearWrapEvent;
+use crate::{EventContext, FtEventHandler, FtHandlerError};
+
+/// Any event that is passed to an [`FtEventHandler`] with an [`EventContext`],
+/// named after the handler method that receives it
+#[derive(Clone, Debug)]
+pub enum HandlerEvent {
+    Mint(FtMintEvent),
+    Transfer(FtTransferEvent),
+    Burn(FtBurnEvent),
+    MtMint(MtMintEvent),
+    MtTransfer(MtTransferEvent),
+    MtBurn(MtBurnEvent),
+    RevertedMint(FtMintEvent, TxExecutionError),
+    RevertedTransfer(FtTransferEvent, TxExecutionError),
+    RevertedBurn(FtBurnEvent, TxExecutionError),
+    EffectiveTransfer(FtEffectiveTransferEvent),
+    NearWrap(NearWrapEvent),
+    Storage(FtStorageEvent),
+    TokenDiscovered(FtTokenDiscoveredEvent),
+    TokenDeployed(FtTokenDeployedEvent),
+}
+
+impl HandlerEvent {
+    /// Calls the method of `handler` that handles this event
+    pub async fn send_to<H: FtEventHandler + ?Sized>(
+        self,
+        handler: &mut H,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        match self {
+            HandlerEvent::Mint(mint) => handler.handle_mint(mint, context).await,
+            HandlerEvent::Transfer(transfer) => handler.handle_transfer(transfer, context).await,
+            HandlerEvent::Burn(burn) => handler.handle_burn(burn, context).await,
+            HandlerEvent::MtMint(mint) => handler.handle_mt_mint(mint, context).await,
+            HandlerEvent::MtTransfer(transfer) => {
+                handler.handle_mt_transfer(transfer, context).await
+            }
+            HandlerEvent::MtBurn(burn) => handler.handle_mt_burn(burn, context).await,
+            HandlerEvent::RevertedMint(mint, error) => {
+                handler.handle_reverted_mint(mint, context, error).await
+            }
+            HandlerEvent::RevertedTransfer(transfer, error) => {
+                handler
+                    .handle_reverted_transfer(transfer, context, error)
+                    .await
+            }
+            HandlerEvent::RevertedBurn(burn, error) => {
+                handler.handle_reverted_burn(burn, context, error).await
+            }
+            HandlerEvent::EffectiveTransfer(transfer) => {
+                handler.handle_effective_transfer(transfer, context).await
+            }
+            HandlerEvent::NearWrap(wrap) => handler.handle_near_wrap(wrap, context).await,
+            HandlerEvent::Storage(storage) => handler.handle_storage(storage, context).await,
+            HandlerEvent::TokenDiscovered(token) => {
+                handler.handle_token_discovered(token, context).await
+            }
+            HandlerEvent::TokenDeployed(token) => {
+                handler.handle_token_deployed(token, context).await
+            }
+        }
+    }
+}
+
+/// A call of an [`FtEventHandler`] method that has no [`EventContext`]
+#[derive(Clone, Debug)]
+pub enum BlockEvent {
+    NearBalanceChange(NearBalanceChange),
+    SupplyChange(SupplyChange),
+    Flush(BlockHeight),
+}
+
+impl BlockEvent {
+    /// Calls the method of `handler` that handles this event
+    pub async fn send_to<H: FtEventHandler + ?Sized>(
+        self,
+        handler: &mut H,
+    ) -> Result<(), FtHandlerError> {
+        match self {
+            BlockEvent::NearBalanceChange(change) => {
+                handler.handle_near_balance_change(change).await
+            }
+            BlockEvent::SupplyChange(change) => handler.handle_supply_change(change).await,
+            BlockEvent::Flush(block_height) => handler.flush_events(block_height).await,
+        }
+    }
+}
+
+/// Implements [`FtEventHandler`] for a type that handles all events in two
+/// methods: `handle`, which takes a [`HandlerEvent`] and its [`EventContext`],
+/// and `handle_block_event`, which takes a [`BlockEvent`]. Generic parameters
+/// and their bounds are passed in brackets after the type.
+macro_rules! impl_event_handler {
+    ($handler:ty $(, [$($generics:tt)*] where [$($bounds:tt)*])?) => {
+        #[async_trait::async_trait]
+        impl $(<$($generics)*>)? $crate::FtEventHandler for $handler $(where $($bounds)*)? {
+            async fn handle_mint(
+                &mut self,
+                mint: inindexer::near_utils::FtMintEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle($crate::combinators::HandlerEvent::Mint(mint), context)
+                    .await
+            }
+
+            async fn handle_transfer(
+                &mut self,
+                transfer: inindexer::near_utils::FtTransferEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle($crate::combinators::HandlerEvent::Transfer(transfer), context)
+                    .await
+            }
+
+            async fn handle_burn(
+                &mut self,
+                burn: inindexer::near_utils::FtBurnEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle($crate::combinators::HandlerEvent::Burn(burn), context)
+                    .await
+            }
+
+            async fn handle_mt_mint(
+                &mut self,
+                mint: $crate::mt::MtMintEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle($crate::combinators::HandlerEvent::MtMint(mint), context)
+                    .await
+            }
+
+            async fn handle_mt_transfer(
+                &mut self,
+                transfer: $crate::mt::MtTransferEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle($crate::combinators::HandlerEvent::MtTransfer(transfer), context)
+                    .await
+            }
+
+            async fn handle_mt_burn(
+                &mut self,
+                burn: $crate::mt::MtBurnEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle($crate::combinators::HandlerEvent::MtBurn(burn), context)
+                    .await
+            }
+
+            async fn handle_reverted_mint(
+                &mut self,
+                mint: inindexer::near_utils::FtMintEvent,
+                context: $crate::EventContext,
+                error: inindexer::near_indexer_primitives::near_primitives::errors::TxExecutionError,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle(
+                    $crate::combinators::HandlerEvent::RevertedMint(mint, error),
+                    context,
+                )
+                .await
+            }
+
+            async fn handle_reverted_transfer(
+                &mut self,
+                transfer: inindexer::near_utils::FtTransferEvent,
+                context: $crate::EventContext,
+                error: inindexer::near_indexer_primitives::near_primitives::errors::TxExecutionError,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle(
+                    $crate::combinators::HandlerEvent::RevertedTransfer(transfer, error),
+                    context,
+                )
+                .await
+            }
+
+            async fn handle_reverted_burn(
+                &mut self,
+                burn: inindexer::near_utils::FtBurnEvent,
+                context: $crate::EventContext,
+                error: inindexer::near_indexer_primitives::near_primitives::errors::TxExecutionError,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle(
+                    $crate::combinators::HandlerEvent::RevertedBurn(burn, error),
+                    context,
+                )
+                .await
+            }
+
+            async fn handle_effective_transfer(
+                &mut self,
+                transfer: $crate::transfer_call::FtEffectiveTransferEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle(
+                    $crate::combinators::HandlerEvent::EffectiveTransfer(transfer),
+                    context,
+                )
+                .await
+            }
+
+            async fn handle_near_wrap(
+                &mut self,
+                wrap: $crate::wrap::NearWrapEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle($crate::combinators::HandlerEvent::NearWrap(wrap), context)
+                    .await
+            }
+
+            async fn handle_storage(
+                &mut self,
+                storage: $crate::storage::FtStorageEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle($crate::combinators::HandlerEvent::Storage(storage), context)
+                    .await
+            }
+
+            async fn handle_token_discovered(
+                &mut self,
+                token: $crate::discovery::FtTokenDiscoveredEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle(
+                    $crate::combinators::HandlerEvent::TokenDiscovered(token),
+                    context,
+                )
+                .await
+            }
+
+            async fn handle_token_deployed(
+                &mut self,
+                token: $crate::discovery::FtTokenDeployedEvent,
+                context: $crate::EventContext,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle(
+                    $crate::combinators::HandlerEvent::TokenDeployed(token),
+                    context,
+                )
+                .await
+            }
+
+            async fn handle_supply_change(
+                &mut self,
+                change: $crate::supply::SupplyChange,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle_block_event($crate::combinators::BlockEvent::SupplyChange(change))
+                    .await
+            }
+
+            async fn handle_near_balance_change(
+                &mut self,
+                change: $crate::balance_changes::NearBalanceChange,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle_block_event($crate::combinators::BlockEvent::NearBalanceChange(
+                    change,
+                ))
+                .await
+            }
+
+            async fn flush_events(
+                &mut self,
+                block_height: inindexer::near_indexer_primitives::types::BlockHeight,
+            ) -> Result<(), $crate::FtHandlerError> {
+                self.handle_block_event($crate::combinators::BlockEvent::Flush(block_height))
+                    .await
+            }
+        }
+    };
+}
+
+pub(crate) use impl_event_handler;
+
+struct TeeBranch {
+    handler: Box<dyn FtEventHandler>,
+    isolated: bool,
+    errors: u64,
+}
+
+/// Sends every event to all of its handlers, in the order they were added.
+///
+/// A failing handler doesn't stop the event from reaching the handlers after
+/// it. Errors of isolated handlers are logged and counted, and the first
+/// error of any other handler is returned once all handlers were called. If
+/// the runner then retries the block, handlers that succeeded receive its
+/// events again, so they should be idempotent, like
+/// [`PushToRedisStream`](crate::redis_handler::PushToRedisStream).
+#[derive(Default)]
+pub struct Tee {
+    branches: Vec<TeeBranch>,
+}
+
+impl Tee {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_handler(mut self, handler: impl FtEventHandler + 'static) -> Self {
+        self.branches.push(TeeBranch {
+            handler: Box::new(handler),
+            isolated: false,
+            errors: 0,
+        });
+        self
+    }
+
+    /// Adds a handler whose errors never fail the indexer
+    pub fn with_isolated_handler(mut self, handler: impl FtEventHandler + 'static) -> Self {
+        self.branches.push(TeeBranch {
+            handler: Box::new(handler),
+            isolated: true,
+            errors: 0,
+        });
+        self
+    }
+
+    /// How many errors each handler returned, in the order they were added
+    pub fn errors(&self) -> Vec<u64> {
+        self.branches.iter().map(|branch| branch.errors).collect()
+    }
+
+    fn record_error(
+        branch: &mut TeeBranch,
+        index: usize,
+        error: FtHandlerError,
+        result: &mut Result<(), FtHandlerError>,
+    ) {
+        branch.errors += 1;
+        if branch.isolated {
+            log::error!("Isolated handler {index} failed: {error}");
+        } else if result.is_ok() {
+            *result = Err(error);
+        }
+    }
+
+    async fn handle(
+        &mut self,
+        event: HandlerEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        let mut result = Ok(());
+        for (index, branch) in self.branches.iter_mut().enumerate() {
+            if let Err(error) = event
+                .clone()
+                .send_to(branch.handler.as_mut(), context.clone())
+                .await
+            {
+                Self::record_error(branch, index, error, &mut result);
+            }
+        }
+        result
+    }
+
+    async fn handle_block_event(&mut self, event: BlockEvent) -> Result<(), FtHandlerError> {
+        let mut result = Ok(());
+        for (index, branch) in self.branches.iter_mut().enumerate() {
+            if let Err(error) = event.clone().send_to(branch.handler.as_mut()).await {
+                Self::record_error(branch, index, error, &mut result);
+            }
+        }
+        result
+    }
+}
+
+impl_event_handler!(Tee);
+
+/// Passes only the events for which `predicate` returns `true` to the inner
+/// handler. NEAR balance changes and supply changes, which have no
+/// [`EventContext`], are always passed.
+pub struct Filter<H, P> {
+    pub handler: H,
+    predicate: P,
+}
+
+impl<H, P> Filter<H, P>
+where
+    H: FtEventHandler,
+    P: Fn(&HandlerEvent, &EventContext) -> bool + Send + Sync,
+{
+    pub fn new(handler: H, predicate: P) -> Self {
+        Self { handler, predicate }
+    }
+
+    async fn handle(
+        &mut self,
+        event: HandlerEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if (self.predicate)(&event, &context) {
+            event.send_to(&mut self.handler, context).await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn handle_block_event(&mut self, event: BlockEvent) -> Result<(), FtHandlerError> {
+        event.send_to(&mut self.handler).await
+    }
+}
+
+impl_event_handler!(Filter<H, P>, [H, P] where [
+    H: FtEventHandler,
+    P: Fn(&HandlerEvent, &EventContext) -> bool + Send + Sync,
+]);
+
+/// Passes the result of `map` to the inner handler, or nothing if it returns
+/// `None`. The event can be replaced with an event of another kind. NEAR
+/// balance changes and supply changes, which have no [`EventContext`], are
+/// passed unchanged.
+pub struct Map<H, F> {
+    pub handler: H,
+    map: F,
+}
+
+impl<H, F> Map<H, F>
+where
+    H: FtEventHandler,
+    F: Fn(HandlerEvent, EventContext) -> Option<(HandlerEvent, EventContext)> + Send + Sync,
+{
+    pub fn new(handler: H, map: F) -> Self {
+        Self { handler, map }
+    }
+
+    async fn handle(
+        &mut self,
+        event: HandlerEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        match (self.map)(event, context) {
+            Some((event, context)) => event.send_to(&mut self.handler, context).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_block_event(&mut self, event: BlockEvent) -> Result<(), FtHandlerError> {
+        event.send_to(&mut self.handler).await
+    }
+}
+
+impl_event_handler!(Map<H, F>, [H, F] where [
+    H: FtEventHandler,
+    F: Fn(HandlerEvent, EventContext) -> Option<(HandlerEvent, EventContext)> + Send + Sync,
+]);
@@ -0,0 +1,99 @@
+//! Classification of NEAR attached to function calls by what it pays for
+
+use std::path::Path;
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use serde::{Deserialize, Serialize};
+
+use crate::account_pattern::AccountPattern;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DepositCategory {
+    /// Storage staking, such as NEP-145 `storage_deposit`
+    StorageDeposit,
+    /// Staking with a staking pool
+    Staking,
+    /// Wrapping NEAR into wNEAR
+    Wrap,
+    NftPurchase,
+    /// Any other deposit
+    Payment,
+}
+
+/// Deposits to `method_name` on contracts matching `contract` (any contract
+/// if `None`) are in `category`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DepositCategoryRule {
+    pub method_name: String,
+    #[serde(default)]
+    pub contract: Option<AccountPattern>,
+    pub category: DepositCategory,
+}
+
+impl DepositCategoryRule {
+    pub fn new(method_name: &str, category: DepositCategory) -> Self {
+        Self {
+            method_name: method_name.to_owned(),
+            contract: None,
+            category,
+        }
+    }
+
+    pub fn on_contract(mut self, contract: AccountPattern) -> Self {
+        self.contract = Some(contract);
+        self
+    }
+
+    fn matches(&self, contract_id: &AccountId, method_name: &str) -> bool {
+        self.method_name == method_name
+            && self
+                .contract
+                .iter()
+                .all(|contract| contract.matches(contract_id))
+    }
+}
+
+/// Rules are checked in order, and the first matching one is used. Deposits
+/// that match no rule are [`DepositCategory::Payment`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DepositCategoryRules(pub Vec<DepositCategoryRule>);
+
+impl DepositCategoryRules {
+    /// Reads rules from a JSON array of
+    /// `{"method_name": ..., "contract": ..., "category": ...}`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json =
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        serde_json::from_slice(&json)
+            .map_err(|e| format!("Failed to parse {}: {e}", path.display()))
+    }
+
+    pub fn categorize(&self, contract_id: &AccountId, method_name: &str) -> DepositCategory {
+        self.0
+            .iter()
+            .find(|rule| rule.matches(contract_id, method_name))
+            .map_or(DepositCategory::Payment, |rule| rule.category)
+    }
+}
+
+impl Default for DepositCategoryRules {
+    fn default() -> Self {
+        use DepositCategory::*;
+        Self(vec![
+            DepositCategoryRule::new("storage_deposit", StorageDeposit),
+            DepositCategoryRule::new("deposit_and_stake", Staking),
+            DepositCategoryRule::new("deposit", Staking)
+                .on_contract("*.poolv1.near".parse().unwrap()),
+            DepositCategoryRule::new("deposit", Staking)
+                .on_contract("*.pool.near".parse().unwrap()),
+            DepositCategoryRule::new("near_deposit", Wrap),
+            DepositCategoryRule::new("nft_buy", NftPurchase),
+            DepositCategoryRule::new("buy", NftPurchase)
+                .on_contract("marketplace.paras.near".parse().unwrap()),
+            DepositCategoryRule::new("buy", NftPurchase)
+                .on_contract("simple.market.mintbase1.near".parse().unwrap()),
+        ])
+    }
+}
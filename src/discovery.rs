@@ -0,0 +1,275 @@
+//! Discovery of new tokens: a registry of contracts that emitted NEP-141
+//! events, used to announce tokens the first time they emit one, and
+//! detection of token code deployments
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_indexer_primitives::views::{ActionView, ReceiptEnumView};
+use inindexer::near_indexer_primitives::CryptoHash;
+use inindexer::TransactionReceipt;
+use serde::{Deserialize, Serialize};
+
+use crate::account_pattern::AccountPattern;
+use crate::snapshot::{is_processed, SnapshotSchedule};
+use crate::spill_queue::write_atomically;
+use crate::{EventContext, FtEvent};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FtEventKind {
+    Mint,
+    Transfer,
+    Burn,
+}
+
+impl From<&FtEvent> for FtEventKind {
+    fn from(event: &FtEvent) -> Self {
+        match event {
+            FtEvent::Mint(_) => FtEventKind::Mint,
+            FtEvent::Transfer(_) => FtEventKind::Transfer,
+            FtEvent::Burn(_) => FtEventKind::Burn,
+        }
+    }
+}
+
+/// A contract emitted its first NEP-141 event
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FtTokenDiscoveredEvent {
+    pub token_id: AccountId,
+    pub first_block_height: BlockHeight,
+    pub first_transaction_id: CryptoHash,
+    pub first_event_kind: FtEventKind,
+    /// [`EventContext::event_id`] of the first event
+    pub first_event_id: String,
+}
+
+/// What is known about a token contract
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenStats {
+    pub first_block_height: BlockHeight,
+    pub first_transaction_id: CryptoHash,
+    pub first_event_kind: FtEventKind,
+    pub last_block_height: BlockHeight,
+    pub event_count: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct RegistryFile {
+    block_height: Option<BlockHeight>,
+    tokens: HashMap<AccountId, TokenStats>,
+}
+
+/// Contracts that emitted NEP-141 events, with counters of their events.
+///
+/// A persistent registry is saved after each block where a new token was
+/// discovered, so tokens are not announced again after a restart. Changes of
+/// the counters are only saved every few blocks, so counters of the blocks
+/// after the last save are lost if the indexer stops. Events of blocks that
+/// were already saved, for example when blocks are processed again after a
+/// restart, are not counted again.
+#[derive(Default)]
+pub struct TokenRegistry {
+    snapshots: SnapshotSchedule,
+    /// Last block whose events are counted
+    block_height: Option<BlockHeight>,
+    tokens: HashMap<AccountId, TokenStats>,
+    /// Counters changed since the last save
+    changed: bool,
+    /// Tokens were added since the last save
+    discovered: bool,
+}
+
+impl TokenRegistry {
+    /// Registry that is only kept in memory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the registry saved at `path`, or an empty one if the file
+    /// doesn't exist yet. Changed counters are saved after every
+    /// `save_interval` blocks.
+    pub fn open(path: impl Into<PathBuf>, save_interval: BlockHeight) -> std::io::Result<Self> {
+        let path = path.into();
+        let file = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RegistryFile::default(),
+            Err(e) => return Err(e),
+        };
+        let mut snapshots = SnapshotSchedule::restored(file.block_height);
+        snapshots.enable(path, save_interval);
+        Ok(Self {
+            snapshots,
+            block_height: file.block_height,
+            tokens: file.tokens,
+            ..Default::default()
+        })
+    }
+
+    pub fn get(&self, contract_id: &AccountId) -> Option<&TokenStats> {
+        self.tokens.get(contract_id)
+    }
+
+    pub fn tokens(&self) -> &HashMap<AccountId, TokenStats> {
+        &self.tokens
+    }
+
+    /// Counts a successful token event, and returns the discovery event if
+    /// it's the first event of the contract
+    pub(crate) fn record(
+        &mut self,
+        event: &FtEvent,
+        context: &EventContext,
+    ) -> Option<FtTokenDiscoveredEvent> {
+        if is_processed(self.block_height, context.block_height) {
+            return None;
+        }
+        if let Some(stats) = self.tokens.get_mut(&context.contract_id) {
+            stats.last_block_height = context.block_height;
+            stats.event_count += 1;
+            self.changed = true;
+            return None;
+        }
+        self.discovered = true;
+        let first_event_kind = FtEventKind::from(event);
+        self.tokens.insert(
+            context.contract_id.clone(),
+            TokenStats {
+                first_block_height: context.block_height,
+                first_transaction_id: context.transaction_id,
+                first_event_kind,
+                last_block_height: context.block_height,
+                event_count: 1,
+            },
+        );
+        Some(FtTokenDiscoveredEvent {
+            token_id: context.contract_id.clone(),
+            first_block_height: context.block_height,
+            first_transaction_id: context.transaction_id,
+            first_event_kind,
+            first_event_id: context.event_id(),
+        })
+    }
+
+    /// Marks the events of the block as counted, and saves the registry if
+    /// it's persistent and a save is due
+    pub(crate) fn finish_block(&mut self, block_height: BlockHeight) -> std::io::Result<()> {
+        if is_processed(self.block_height, block_height) {
+            return Ok(());
+        }
+        self.block_height = Some(block_height);
+        let path = if self.discovered {
+            self.snapshots.path()
+        } else if self.changed {
+            self.snapshots.due(block_height)
+        } else {
+            None
+        };
+        if let Some(path) = path {
+            save(path, self.block_height, &self.tokens)?;
+            self.snapshots.saved(block_height);
+            self.changed = false;
+            self.discovered = false;
+        }
+        Ok(())
+    }
+}
+
+fn save(
+    path: &Path,
+    block_height: Option<BlockHeight>,
+    tokens: &HashMap<AccountId, TokenStats>,
+) -> std::io::Result<()> {
+    #[derive(Serialize)]
+    struct RegistryFileRef<'a> {
+        block_height: Option<BlockHeight>,
+        tokens: &'a HashMap<AccountId, TokenStats>,
+    }
+
+    let json = serde_json::to_vec(&RegistryFileRef {
+        block_height,
+        tokens,
+    })?;
+    write_atomically(path, &json)
+}
+
+/// Which deployments are reported as new tokens
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenDeploymentOptions {
+    /// Hashes of the code of known FT implementations
+    pub known_code_hashes: HashSet<CryptoHash>,
+    /// Accounts created by token factories, where any deployed code is a token
+    pub factories: Vec<AccountPattern>,
+}
+
+impl Default for TokenDeploymentOptions {
+    fn default() -> Self {
+        Self {
+            known_code_hashes: HashSet::new(),
+            factories: vec![
+                "*.tkn.near".parse().unwrap(),
+                "*.meme-cooking.near".parse().unwrap(),
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum DeploymentMatch {
+    /// The code is one of [`TokenDeploymentOptions::known_code_hashes`]
+    CodeHash,
+    /// The account is one of [`TokenDeploymentOptions::factories`]
+    Factory,
+}
+
+/// FT code was deployed to a contract, which may not have emitted any events
+/// yet
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FtTokenDeployedEvent {
+    pub token_id: AccountId,
+    pub code_hash: CryptoHash,
+    pub matched_by: DeploymentMatch,
+}
+
+/// Finds `DeployContract` actions of token code in a successful receipt.
+/// Returns the index of the action with each event.
+pub(crate) fn get_token_deployments(
+    receipt: &TransactionReceipt,
+    options: &TokenDeploymentOptions,
+) -> Vec<(usize, FtTokenDeployedEvent)> {
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return Vec::new();
+    };
+    let contract_id = &receipt.receipt.receipt.receiver_id;
+    actions
+        .iter()
+        .enumerate()
+        .filter_map(|(action_index, action)| {
+            // Receipt views only contain the hash of the deployed code
+            let ActionView::DeployContract { code, .. } = action else {
+                return None;
+            };
+            let code_hash = CryptoHash::try_from(code.as_slice()).ok()?;
+            let matched_by = if options.known_code_hashes.contains(&code_hash) {
+                DeploymentMatch::CodeHash
+            } else if options
+                .factories
+                .iter()
+                .any(|factory| factory.matches(contract_id))
+            {
+                DeploymentMatch::Factory
+            } else {
+                return None;
+            };
+            Some((
+                action_index,
+                FtTokenDeployedEvent {
+                    token_id: contract_id.clone(),
+                    code_hash,
+                    matched_by,
+                },
+            ))
+        })
+        .collect()
+}
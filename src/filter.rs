@@ -0,0 +1,109 @@
+//! Filtering of events by token contract, by the accounts involved, and by
+//! amount
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use serde::{Deserialize, Serialize};
+
+use crate::account_pattern::AccountPattern;
+use crate::serde_utils::option_dec_format;
+use crate::{EventContext, FtEvent};
+
+/// An event passes the filter if all of the configured conditions are met.
+/// The default filter passes everything.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventFilter {
+    /// Only events of these contracts pass. Native NEAR has contract `near`.
+    pub allowed_tokens: Option<Vec<AccountPattern>>,
+    pub denied_tokens: Vec<AccountPattern>,
+    /// Only events where at least one of the accounts (sender, receiver,
+    /// owner) matches pass. Events that don't involve accounts, such as
+    /// supply changes, are not affected.
+    pub allowed_accounts: Option<Vec<AccountPattern>>,
+    /// Events where any of the accounts matches are dropped
+    pub denied_accounts: Vec<AccountPattern>,
+    /// Events with a smaller amount are dropped. Events that don't have an
+    /// amount are not affected.
+    #[serde(with = "option_dec_format")]
+    pub min_amount: Option<u128>,
+}
+
+impl EventFilter {
+    pub fn allows(
+        &self,
+        contract_id: &AccountId,
+        accounts: &[&AccountId],
+        amount: Option<u128>,
+    ) -> bool {
+        let matches_any = |patterns: &[AccountPattern], account_id: &AccountId| {
+            patterns.iter().any(|pattern| pattern.matches(account_id))
+        };
+        if let Some(allowed_tokens) = &self.allowed_tokens {
+            if !matches_any(allowed_tokens, contract_id) {
+                return false;
+            }
+        }
+        if matches_any(&self.denied_tokens, contract_id) {
+            return false;
+        }
+        if let Some(allowed_accounts) = &self.allowed_accounts {
+            if !accounts.is_empty()
+                && !accounts
+                    .iter()
+                    .any(|account_id| matches_any(allowed_accounts, account_id))
+            {
+                return false;
+            }
+        }
+        if accounts
+            .iter()
+            .any(|account_id| matches_any(&self.denied_accounts, account_id))
+        {
+            return false;
+        }
+        match (self.min_amount, amount) {
+            (Some(min_amount), Some(amount)) => amount >= min_amount,
+            _ => true,
+        }
+    }
+
+    pub fn allows_ft_event(&self, event: &FtEvent, context: &EventContext) -> bool {
+        match event {
+            FtEvent::Mint(mint) => {
+                self.allows(&context.contract_id, &[&mint.owner_id], Some(mint.amount))
+            }
+            FtEvent::Transfer(transfer) => self.allows(
+                &context.contract_id,
+                &[&transfer.old_owner_id, &transfer.new_owner_id],
+                Some(transfer.amount),
+            ),
+            FtEvent::Burn(burn) => {
+                self.allows(&context.contract_id, &[&burn.owner_id], Some(burn.amount))
+            }
+        }
+    }
+}
+
+/// Filters loaded from a file: one for everything that [`FtIndexer`](crate::FtIndexer)
+/// passes to the handler, and one for each Redis stream
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    pub indexer: EventFilter,
+    pub streams: HashMap<String, EventFilter>,
+}
+
+impl FilterConfig {
+    /// Reads the config from a JSON file, for example
+    /// `{"indexer": {"allowed_tokens": ["*.sweat"]}, "streams": {"ft_transfer": {"min_amount": "1000"}}}`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json =
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        serde_json::from_slice(&json)
+            .map_err(|e| format!("Failed to parse {}: {e}", path.display()))
+    }
+}
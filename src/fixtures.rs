@@ -0,0 +1,121 @@
+//! Recording and replaying blocks from disk, so the indexer can be tested
+//! deterministically without network access.
+//!
+//! Fixtures for a block range live in their own directory, one gzipped
+//! `StreamerMessage` per block (`{height}.json.gz`). A `complete` marker is
+//! written once the whole range has been recorded, so an interrupted recording
+//! is never mistaken for a valid fixture.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use inindexer::message_provider::MessageProvider;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use inindexer::near_indexer_primitives::StreamerMessage;
+use inindexer::{run_indexer, Indexer, IndexerOptions, MessageStreamer};
+
+const COMPLETE_MARKER: &str = "complete";
+
+/// Directory where the fixtures for `start_inclusive..end_exclusive` are stored.
+pub fn range_dir(
+    fixtures_dir: impl AsRef<Path>,
+    start_inclusive: BlockHeight,
+    end_exclusive: BlockHeight,
+) -> PathBuf {
+    fixtures_dir
+        .as_ref()
+        .join(format!("{start_inclusive}-{end_exclusive}"))
+}
+
+/// Whether a recording into `dir` has finished
+pub fn is_recorded(dir: impl AsRef<Path>) -> bool {
+    dir.as_ref().join(COMPLETE_MARKER).exists()
+}
+
+fn block_path(dir: &Path, block_height: BlockHeight) -> PathBuf {
+    dir.join(format!("{block_height}.json.gz"))
+}
+
+/// Serves blocks previously saved by [`record_fixtures`].
+///
+/// Heights that have no file are reported as missing, the same way a network
+/// provider reports skipped blocks.
+#[derive(Debug, Clone)]
+pub struct FileProvider {
+    dir: PathBuf,
+}
+
+impl FileProvider {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl MessageProvider for FileProvider {
+    type Error = String;
+
+    async fn get_message(
+        &self,
+        block_height: BlockHeight,
+    ) -> Result<Option<StreamerMessage>, Self::Error> {
+        let path = block_path(&self.dir, block_height);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = std::fs::File::open(&path)
+            .map_err(|e| format!("Failed to open {}: {e}", path.display()))?;
+        let mut json = String::new();
+        GzDecoder::new(file)
+            .read_to_string(&mut json)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| format!("Failed to parse {}: {e}", path.display()))
+    }
+}
+
+/// Indexer that only writes every block it receives to a directory.
+struct FixtureRecorder {
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl Indexer for FixtureRecorder {
+    type Error = String;
+
+    async fn process_block_start(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
+        let path = block_path(&self.dir, block.block.header.height);
+        let json = serde_json::to_vec(block).map_err(|e| e.to_string())?;
+        let file = std::fs::File::create(&path)
+            .map_err(|e| format!("Failed to create {}: {e}", path.display()))?;
+        let mut encoder = GzEncoder::new(file, Compression::best());
+        encoder
+            .write_all(&json)
+            .and_then(|()| encoder.finish().map(|_| ()))
+            .map_err(|e| format!("Failed to write {}: {e}", path.display()))
+    }
+}
+
+/// Streams the range configured in `options` from `streamer` and saves every
+/// block to `dir`, to be replayed later with [`FileProvider`].
+pub async fn record_fixtures(
+    streamer: impl MessageStreamer,
+    dir: impl AsRef<Path>,
+    options: IndexerOptions,
+) -> Result<(), String> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    let mut recorder = FixtureRecorder {
+        dir: dir.to_path_buf(),
+    };
+    run_indexer(&mut recorder, streamer, options)
+        .await
+        .map_err(|e| format!("Recording failed: {e:?}"))?;
+    std::fs::write(dir.join(COMPLETE_MARKER), b"")
+        .map_err(|e| format!("Failed to mark {} as complete: {e}", dir.display()))
+}
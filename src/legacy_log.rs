@@ -0,0 +1,106 @@
+//! Parsers of plain text token logs that were used before NEP-297 events
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use inindexer::near_utils::FtTransferEvent;
+
+use crate::account_pattern::AccountPattern;
+use crate::{EventContext, FtEvent, ReceiptEvent};
+
+pub trait LegacyLogParser: Send + Sync {
+    /// Returns the events described by the log, or `None` if the log is not
+    /// in this format
+    fn parse(&self, log: &str) -> Option<Vec<FtEvent>>;
+}
+
+/// `Transfer {amount} from {from} to {to}`, logged by TKN tokens
+pub struct TknTransferLogParser;
+
+impl LegacyLogParser for TknTransferLogParser {
+    fn parse(&self, log: &str) -> Option<Vec<FtEvent>> {
+        let (amount, owners) = log.strip_prefix("Transfer ")?.split_once(" from ")?;
+        let (from, to) = owners.split_once(" to ")?;
+        Some(vec![FtEvent::Transfer(FtTransferEvent {
+            old_owner_id: from.parse().ok()?,
+            new_owner_id: to.parse().ok()?,
+            amount: amount.parse().ok()?,
+            memo: None,
+        })])
+    }
+}
+
+/// Contracts whose logs a parser is applied to
+#[derive(Clone, Debug)]
+pub enum ContractScope {
+    Any,
+    Only(Vec<AccountPattern>),
+}
+
+impl ContractScope {
+    pub fn contains(&self, contract_id: &AccountId) -> bool {
+        match self {
+            ContractScope::Any => true,
+            ContractScope::Only(patterns) => {
+                patterns.iter().any(|pattern| pattern.matches(contract_id))
+            }
+        }
+    }
+}
+
+pub(crate) struct ScopedLegacyLogParser {
+    pub parser: Box<dyn LegacyLogParser>,
+    pub scope: ContractScope,
+}
+
+/// Parsers that [`FtIndexer`](crate::FtIndexer) starts with. The TKN parser
+/// is only applied to tokens of the `tkn.near` factory, since any contract
+/// can log text in the same format.
+pub(crate) fn default_legacy_log_parsers() -> Vec<ScopedLegacyLogParser> {
+    vec![ScopedLegacyLogParser {
+        parser: Box::new(TknTransferLogParser),
+        scope: ContractScope::Only(vec!["*.tkn.near".parse().unwrap()]),
+    }]
+}
+
+/// Whether two events describe the same movement of tokens. Memo is ignored,
+/// since legacy logs don't have it.
+fn is_same_movement(a: &FtEvent, b: &FtEvent) -> bool {
+    match (a, b) {
+        (FtEvent::Mint(a), FtEvent::Mint(b)) => a.owner_id == b.owner_id && a.amount == b.amount,
+        (FtEvent::Transfer(a), FtEvent::Transfer(b)) => {
+            a.old_owner_id == b.old_owner_id
+                && a.new_owner_id == b.new_owner_id
+                && a.amount == b.amount
+        }
+        (FtEvent::Burn(a), FtEvent::Burn(b)) => a.owner_id == b.owner_id && a.amount == b.amount,
+        _ => false,
+    }
+}
+
+/// Removes events parsed from legacy logs (at `legacy_indices`, in increasing
+/// order) that the receipt also emitted as standard events. Each standard
+/// event can only cancel out one legacy event. Returns how many were removed.
+pub(crate) fn remove_duplicate_legacy_events(
+    events: &mut Vec<(ReceiptEvent, EventContext)>,
+    legacy_indices: &[usize],
+) -> usize {
+    let mut matched_standard_indices = Vec::new();
+    let mut duplicate_indices = Vec::new();
+    for &legacy_index in legacy_indices {
+        let ReceiptEvent::Ft(legacy_event) = &events[legacy_index].0 else {
+            continue;
+        };
+        let standard_index = (0..events.len()).find(|index| {
+            !legacy_indices.contains(index)
+                && !matched_standard_indices.contains(index)
+                && matches!(&events[*index].0, ReceiptEvent::Ft(event) if is_same_movement(event, legacy_event))
+        });
+        if let Some(standard_index) = standard_index {
+            matched_standard_indices.push(standard_index);
+            duplicate_indices.push(legacy_index);
+        }
+    }
+    for &index in duplicate_indices.iter().rev() {
+        events.remove(index);
+    }
+    duplicate_indices.len()
+}
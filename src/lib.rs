@@ -1,172 +1,773 @@
+pub mod account_pattern;
+pub mod balance_changes;
+pub mod balance_tracker;
+pub mod combinators;
+pub mod deposit_category;
+pub mod discovery;
+pub mod filter;
+pub mod fixtures;
+pub mod legacy_log;
+pub mod mt;
+pub mod reconciliation;
 pub mod redis_handler;
+pub mod serde_utils;
+mod snapshot;
+pub mod spill_queue;
+pub mod storage;
+pub mod supply;
+pub mod transfer_call;
+pub mod verification;
+pub mod wrap;
 
 use async_trait::async_trait;
+use inindexer::near_indexer_primitives::near_primitives::errors::TxExecutionError;
 use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
-use inindexer::near_indexer_primitives::views::{ActionView, ReceiptEnumView};
+use inindexer::near_indexer_primitives::views::{ActionView, ExecutionStatusView, ReceiptEnumView};
 use inindexer::near_indexer_primitives::CryptoHash;
 use inindexer::near_indexer_primitives::StreamerMessage;
 use inindexer::near_utils::{
     EventLogData, FtBurnEvent, FtBurnLog, FtMintEvent, FtMintLog, FtTransferEvent, FtTransferLog,
 };
 use inindexer::{IncompleteTransaction, Indexer, TransactionReceipt};
+use serde::Serialize;
+
+use crate::balance_changes::{NearBalanceChange, NearBalanceChanges};
+use crate::deposit_category::{DepositCategory, DepositCategoryRules};
+use crate::discovery::{
+    get_token_deployments, FtTokenDeployedEvent, FtTokenDiscoveredEvent, TokenDeploymentOptions,
+    TokenRegistry,
+};
+use crate::filter::EventFilter;
+use crate::legacy_log::{
+    default_legacy_log_parsers, remove_duplicate_legacy_events, ContractScope, LegacyLogParser,
+    ScopedLegacyLogParser,
+};
+use crate::mt::{MtBurnEvent, MtEventLog, MtMintEvent, MtTransferEvent};
+use crate::storage::{get_storage_events, FtStorageEvent};
+use crate::supply::SupplyChange;
+use crate::transfer_call::{
+    find_transfer_call_event_id, get_method_name, get_resolve_transfer_args,
+    get_transfer_call_context, get_used_amount, FtEffectiveTransferEvent, TransferCallContext,
+};
+use crate::verification::{TokenVerificationOptions, TokenVerifier, UnverifiedTokenPolicy};
+use crate::wrap::{get_wrap_event, NearWrapEvent};
 
 #[async_trait]
 pub trait FtEventHandler: Send + Sync {
-    async fn handle_mint(&mut self, mint: FtMintEvent, context: EventContext);
-    async fn handle_transfer(&mut self, transfer: FtTransferEvent, context: EventContext);
-    async fn handle_burn(&mut self, burn: FtBurnEvent, context: EventContext);
+    async fn handle_mint(
+        &mut self,
+        mint: FtMintEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError>;
+    async fn handle_transfer(
+        &mut self,
+        transfer: FtTransferEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError>;
+    async fn handle_burn(
+        &mut self,
+        burn: FtBurnEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError>;
+
+    async fn handle_mt_mint(
+        &mut self,
+        _mint: MtMintEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+    async fn handle_mt_transfer(
+        &mut self,
+        _transfer: MtTransferEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+    async fn handle_mt_burn(
+        &mut self,
+        _burn: MtBurnEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    /// Called instead of [`handle_mint`](Self::handle_mint) for events of
+    /// failed receipts, if [`FtIndexerOptions::index_reverted_events`] is
+    /// enabled. These events didn't change any balances.
+    async fn handle_reverted_mint(
+        &mut self,
+        _mint: FtMintEvent,
+        _context: EventContext,
+        _error: TxExecutionError,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+    /// Called instead of [`handle_transfer`](Self::handle_transfer) for events
+    /// and NEAR deposits of failed receipts, if
+    /// [`FtIndexerOptions::index_reverted_events`] is enabled. The deposits
+    /// are refunded to the sender.
+    async fn handle_reverted_transfer(
+        &mut self,
+        _transfer: FtTransferEvent,
+        _context: EventContext,
+        _error: TxExecutionError,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+    /// Called instead of [`handle_burn`](Self::handle_burn) for events of
+    /// failed receipts, if [`FtIndexerOptions::index_reverted_events`] is
+    /// enabled
+    async fn handle_reverted_burn(
+        &mut self,
+        _burn: FtBurnEvent,
+        _context: EventContext,
+        _error: TxExecutionError,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    /// Called after the refunds of `ft_transfer_call` are made, if
+    /// [`FtIndexerOptions::emit_effective_transfers`] is enabled
+    async fn handle_effective_transfer(
+        &mut self,
+        _transfer: FtEffectiveTransferEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    /// Called after the events of a `near_deposit` or `near_withdraw` receipt
+    /// on [`FtIndexerOptions::wrap_contract_id`]
+    async fn handle_near_wrap(
+        &mut self,
+        _wrap: NearWrapEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    /// Called after the events of a successful NEP-145 `storage_deposit`,
+    /// `storage_withdraw` or `storage_unregister` call. These are detected on
+    /// any contract, so check [`EventContext::token_verified`] if
+    /// [`FtIndexerOptions::token_verification`] is enabled.
+    async fn handle_storage(
+        &mut self,
+        _storage: FtStorageEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    /// Called with the mints and burns of a token in the block, by handlers
+    /// such as [`SupplyTracker`](crate::supply::SupplyTracker) that track
+    /// supply
+    async fn handle_supply_change(&mut self, _change: SupplyChange) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    /// Called before the first successful NEP-141 event of a contract that is
+    /// not in [`FtIndexer::token_registry`], with the context of that event
+    async fn handle_token_discovered(
+        &mut self,
+        _token: FtTokenDiscoveredEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    /// Called when code of a token is deployed to a contract, see
+    /// [`FtIndexerOptions::token_deployments`]
+    async fn handle_token_deployed(
+        &mut self,
+        _token: FtTokenDeployedEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    /// Called for every change of an account's NEAR balance in the block's
+    /// state changes, before the receipts of the block are processed
+    async fn handle_near_balance_change(
+        &mut self,
+        _change: NearBalanceChange,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
 
     /// Called after each block
-    async fn flush_events(&mut self, block_height: BlockHeight);
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), FtHandlerError>;
 }
 
-pub struct FtIndexer<T: FtEventHandler + Send + Sync + 'static>(pub T);
+/// A NEP-141 event, or a native NEAR transfer if `contract_id` in its
+/// [`EventContext`] is `near`
+#[derive(Clone, Debug)]
+pub enum FtEvent {
+    Mint(FtMintEvent),
+    Transfer(FtTransferEvent),
+    Burn(FtBurnEvent),
+}
 
-#[async_trait]
-impl<T: FtEventHandler + Send + Sync + 'static> Indexer for FtIndexer<T> {
-    type Error = String;
+/// An event found in a receipt, before it's passed to the handler
+enum ReceiptEvent {
+    Ft(FtEvent),
+    MtMint(MtMintEvent),
+    MtTransfer(MtTransferEvent),
+    MtBurn(MtBurnEvent),
+}
 
-    async fn on_receipt(
+impl ReceiptEvent {
+    /// Multi token events pass the amount filter if any of their amounts does
+    fn passes(&self, filter: &EventFilter, context: &EventContext) -> bool {
+        let max_amount = |amounts: &[u128]| amounts.iter().max().copied();
+        match self {
+            ReceiptEvent::Ft(event) => filter.allows_ft_event(event, context),
+            ReceiptEvent::MtMint(mint) => filter.allows(
+                &context.contract_id,
+                &[&mint.owner_id],
+                max_amount(&mint.amounts),
+            ),
+            ReceiptEvent::MtTransfer(transfer) => filter.allows(
+                &context.contract_id,
+                &[&transfer.old_owner_id, &transfer.new_owner_id],
+                max_amount(&transfer.amounts),
+            ),
+            ReceiptEvent::MtBurn(burn) => filter.allows(
+                &context.contract_id,
+                &[&burn.owner_id],
+                max_amount(&burn.amounts),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FtIndexerOptions {
+    /// Also index token events and NEAR deposits of failed receipts, see
+    /// [`FtEventHandler::handle_reverted_transfer`]
+    pub index_reverted_events: bool,
+    /// Emit the net amount of every `ft_transfer_call` after the refund, see
+    /// [`FtEventHandler::handle_effective_transfer`]
+    pub emit_effective_transfers: bool,
+    /// wNEAR contract, see [`FtEventHandler::handle_near_wrap`]
+    pub wrap_contract_id: AccountId,
+    /// Check that contracts emitting token events are known tokens, see
+    /// [`EventContext::token_verified`]
+    pub token_verification: Option<TokenVerificationOptions>,
+    /// Rules for [`EventContext::deposit_category`] of NEAR attached to
+    /// function calls
+    pub deposit_categories: DepositCategoryRules,
+    /// Only events that pass this filter are passed to the handler. Events
+    /// that are dropped are still used to link related events, such as
+    /// [`EventContext::refund_of`].
+    pub filter: EventFilter,
+    /// Code hashes and factories of tokens, see
+    /// [`FtEventHandler::handle_token_deployed`]
+    pub token_deployments: TokenDeploymentOptions,
+}
+
+impl Default for FtIndexerOptions {
+    fn default() -> Self {
+        Self {
+            index_reverted_events: false,
+            emit_effective_transfers: false,
+            wrap_contract_id: "wrap.near".parse().unwrap(),
+            token_verification: None,
+            deposit_categories: DepositCategoryRules::default(),
+            filter: EventFilter::default(),
+            token_deployments: TokenDeploymentOptions::default(),
+        }
+    }
+}
+
+pub struct FtIndexer<T: FtEventHandler + Send + Sync + 'static> {
+    pub handler: T,
+    pub options: FtIndexerOptions,
+    near_balance_changes: NearBalanceChanges,
+    legacy_log_parsers: Vec<ScopedLegacyLogParser>,
+    suppressed_duplicates: u64,
+    token_verifier: TokenVerifier,
+    token_registry: TokenRegistry,
+}
+
+impl<T: FtEventHandler + Send + Sync + 'static> FtIndexer<T> {
+    pub fn new(handler: T) -> Self {
+        Self::with_options(handler, FtIndexerOptions::default())
+    }
+
+    pub fn with_options(handler: T, options: FtIndexerOptions) -> Self {
+        Self {
+            handler,
+            options,
+            near_balance_changes: NearBalanceChanges::new(),
+            legacy_log_parsers: default_legacy_log_parsers(),
+            suppressed_duplicates: 0,
+            token_verifier: TokenVerifier::default(),
+            token_registry: TokenRegistry::new(),
+        }
+    }
+
+    /// Replaces the tracker of NEAR balance changes, for example with one from
+    /// [`NearBalanceChanges::with_capacity`] to keep more or fewer balances
+    pub fn with_near_balance_changes(mut self, near_balance_changes: NearBalanceChanges) -> Self {
+        self.near_balance_changes = near_balance_changes;
+        self
+    }
+
+    /// Replaces the in-memory registry of token contracts, for example with a
+    /// persistent one from [`TokenRegistry::open`]
+    pub fn with_token_registry(mut self, token_registry: TokenRegistry) -> Self {
+        self.token_registry = token_registry;
+        self
+    }
+
+    /// Contracts that emitted NEP-141 events, with counters of their events
+    pub fn token_registry(&self) -> &TokenRegistry {
+        &self.token_registry
+    }
+
+    /// How many events from non-NEP-297 logs were dropped because the same
+    /// receipt also emitted them as NEP-141 events
+    pub fn suppressed_duplicates(&self) -> u64 {
+        self.suppressed_duplicates
+    }
+
+    /// Adds a parser of non-NEP-297 logs, applied to logs of contracts in
+    /// `scope`. The TKN `Transfer {amount} from {from} to {to}` parser is
+    /// registered by default for `*.tkn.near`. Only the first parser that
+    /// recognizes a log is used.
+    pub fn register_legacy_log_parser(
+        &mut self,
+        parser: impl LegacyLogParser + 'static,
+        scope: ContractScope,
+    ) {
+        self.legacy_log_parsers.push(ScopedLegacyLogParser {
+            parser: Box::new(parser),
+            scope,
+        });
+    }
+
+    /// Removes all parsers of non-NEP-297 logs, including the default ones
+    pub fn clear_legacy_log_parsers(&mut self) {
+        self.legacy_log_parsers.clear();
+    }
+
+    /// Passes the events of a receipt to the handler. [`Indexer::on_receipt`]
+    /// calls it for every receipt in the block.
+    pub async fn process_receipt(
         &mut self,
         receipt: &TransactionReceipt,
         transaction: &IncompleteTransaction,
-        _block: &StreamerMessage,
-    ) -> Result<(), Self::Error> {
-        let get_context_lazy = || {
-            let predecessor_id = receipt.receipt.receipt.predecessor_id.clone();
-            let contract_id = receipt.receipt.receipt.receiver_id.clone();
-            let transaction_id = transaction.transaction.transaction.hash;
-            let receipt_id = receipt.receipt.receipt.receipt_id;
-            let block_height = receipt.block_height;
-            let block_timestamp_nanosec = receipt.block_timestamp_nanosec;
-            EventContext {
-                transaction_id,
-                receipt_id,
-                block_height,
-                block_timestamp_nanosec,
-                predecessor_id,
-                contract_id,
+    ) -> Result<(), FtIndexerError> {
+        let failure = if receipt.is_successful(false) {
+            None
+        } else {
+            match &receipt.receipt.execution_outcome.outcome.status {
+                ExecutionStatusView::Failure(error) if self.options.index_reverted_events => {
+                    Some(error.clone())
+                }
+                _ => return Ok(()),
             }
         };
-        if receipt.is_successful(false) {
-            for log in &receipt.receipt.execution_outcome.outcome.logs {
-                if let Some(tkn_log) = log.strip_prefix("Transfer ") {
-                    if let Some((amount, owners)) = tkn_log.split_once(" from ") {
-                        let Ok(amount) = amount.parse::<u128>() else {
-                            continue;
-                        };
-                        if let Some((from, to)) = owners.split_once(" to ") {
-                            let Ok(from) = from.parse::<AccountId>() else {
-                                continue;
-                            };
-                            let Ok(to) = to.parse::<AccountId>() else {
-                                continue;
-                            };
-                            let transfer = FtTransferEvent {
-                                old_owner_id: from,
-                                new_owner_id: to,
-                                amount,
-                                memo: None,
-                            };
-                            self.0.handle_transfer(transfer, get_context_lazy()).await;
+        let relayer_id = get_relayer_id(transaction);
+        let method_name = get_method_name(receipt);
+        let transfer_call = get_transfer_call_context(receipt);
+        let base_context = || EventContext {
+            transaction_id: transaction.transaction.transaction.hash,
+            receipt_id: receipt.receipt.receipt.receipt_id,
+            block_height: receipt.block_height,
+            block_timestamp_nanosec: receipt.block_timestamp_nanosec,
+            predecessor_id: receipt.receipt.receipt.predecessor_id.clone(),
+            contract_id: receipt.receipt.receipt.receiver_id.clone(),
+            log_index: None,
+            event_index: None,
+            action_index: None,
+            relayer_id: relayer_id.clone(),
+            native_transfer: None,
+            refund_of: None,
+            method_name: method_name.clone(),
+            transfer_call: None,
+            token_verified: None,
+            deposit_category: None,
+        };
+        let get_context_lazy = |log_index: usize, event_index: usize| EventContext {
+            log_index: Some(log_index),
+            event_index: Some(event_index),
+            ..base_context()
+        };
+        let get_native_context = |action_index: usize, kind: NativeTransferKind| EventContext {
+            contract_id: "near".parse().unwrap(),
+            action_index: Some(action_index),
+            native_transfer: Some(kind),
+            method_name: None,
+            ..base_context()
+        };
+
+        let mut events = Vec::new();
+        let mut legacy_event_indices = Vec::new();
+        for (log_index, log) in receipt
+            .receipt
+            .execution_outcome
+            .outcome
+            .logs
+            .iter()
+            .enumerate()
+        {
+            if let Some(legacy_events) = self
+                .legacy_log_parsers
+                .iter()
+                .filter(|parser| parser.scope.contains(&receipt.receipt.receipt.receiver_id))
+                .find_map(|parser| parser.parser.parse(log))
+            {
+                for (event_index, event) in legacy_events.into_iter().enumerate() {
+                    legacy_event_indices.push(events.len());
+                    events.push((
+                        ReceiptEvent::Ft(event),
+                        get_context_lazy(log_index, event_index),
+                    ));
+                }
+            }
+            if log.contains("nep245") {
+                if let Some(mt_log) = MtEventLog::parse(log) {
+                    log::debug!("Multi token log: {mt_log:?}");
+                    match mt_log {
+                        MtEventLog::Mint(mints) => {
+                            for (event_index, mint) in mints.into_iter().enumerate() {
+                                events.push((
+                                    ReceiptEvent::MtMint(mint),
+                                    get_context_lazy(log_index, event_index),
+                                ));
+                            }
+                        }
+                        MtEventLog::Transfer(transfers) => {
+                            for (event_index, transfer) in transfers.into_iter().enumerate() {
+                                events.push((
+                                    ReceiptEvent::MtTransfer(transfer),
+                                    get_context_lazy(log_index, event_index),
+                                ));
+                            }
+                        }
+                        MtEventLog::Burn(burns) => {
+                            for (event_index, burn) in burns.into_iter().enumerate() {
+                                events.push((
+                                    ReceiptEvent::MtBurn(burn),
+                                    get_context_lazy(log_index, event_index),
+                                ));
+                            }
                         }
                     }
-                }
-                if !log.contains("nep141") {
-                    // Don't even start parsing logs if they don't even contain the NEP-141 standard
                     continue;
                 }
-                if let Ok(mint_log) = EventLogData::<FtMintLog>::deserialize(log) {
-                    if mint_log.validate() {
-                        log::debug!("Mint log: {mint_log:?}");
-                        for mint in mint_log.data.0 {
-                            self.0.handle_mint(mint, get_context_lazy()).await;
-                        }
+            }
+            if !log.contains("nep141") {
+                // Don't even start parsing logs if they don't even contain the NEP-141 standard
+                continue;
+            }
+            if let Ok(mint_log) = EventLogData::<FtMintLog>::deserialize(log) {
+                if mint_log.validate() {
+                    log::debug!("Mint log: {mint_log:?}");
+                    for (event_index, mint) in mint_log.data.0.into_iter().enumerate() {
+                        events.push((
+                            ReceiptEvent::Ft(FtEvent::Mint(mint)),
+                            get_context_lazy(log_index, event_index),
+                        ));
                     }
                 }
-                if let Ok(transfer_log) = EventLogData::<FtTransferLog>::deserialize(log) {
-                    if transfer_log.validate() {
-                        log::debug!("Transfer log: {transfer_log:?}");
-                        for transfer in transfer_log.data.0 {
-                            self.0.handle_transfer(transfer, get_context_lazy()).await;
-                        }
+            }
+            if let Ok(transfer_log) = EventLogData::<FtTransferLog>::deserialize(log) {
+                if transfer_log.validate() {
+                    log::debug!("Transfer log: {transfer_log:?}");
+                    for (event_index, transfer) in transfer_log.data.0.into_iter().enumerate() {
+                        events.push((
+                            ReceiptEvent::Ft(FtEvent::Transfer(transfer)),
+                            get_context_lazy(log_index, event_index),
+                        ));
                     }
                 }
-                if let Ok(burn_log) = EventLogData::<FtBurnLog>::deserialize(log) {
-                    if burn_log.validate() {
-                        log::debug!("Burn log: {burn_log:?}");
-                        for burn in burn_log.data.0 {
-                            self.0.handle_burn(burn, get_context_lazy()).await;
-                        }
+            }
+            if let Ok(burn_log) = EventLogData::<FtBurnLog>::deserialize(log) {
+                if burn_log.validate() {
+                    log::debug!("Burn log: {burn_log:?}");
+                    for (event_index, burn) in burn_log.data.0.into_iter().enumerate() {
+                        events.push((
+                            ReceiptEvent::Ft(FtEvent::Burn(burn)),
+                            get_context_lazy(log_index, event_index),
+                        ));
                     }
                 }
             }
+        }
 
-            if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt {
-                if receipt.receipt.receipt.predecessor_id != "system" {
-                    for action in actions {
-                        match action {
-                            ActionView::Transfer { deposit } => {
+        let duplicates = remove_duplicate_legacy_events(&mut events, &legacy_event_indices);
+        if duplicates > 0 {
+            log::debug!(
+                "Suppressed {duplicates} legacy log events duplicated by NEP-141 events in {}",
+                receipt.receipt.receipt.receipt_id
+            );
+            self.suppressed_duplicates += duplicates as u64;
+        }
+
+        if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt {
+            if receipt.receipt.receipt.predecessor_id == "system" {
+                // Refunds are not transfers, except for the balance of an
+                // account deleted with `DeleteAccount`
+                if let Some(deleted_account_id) = get_deleted_account_id(receipt, transaction) {
+                    for (action_index, action) in actions.iter().enumerate() {
+                        if let ActionView::Transfer { deposit } = action {
+                            if *deposit > 0 {
                                 let transfer = FtTransferEvent {
-                                    old_owner_id: receipt.receipt.receipt.predecessor_id.clone(),
+                                    old_owner_id: deleted_account_id.clone(),
                                     new_owner_id: receipt.receipt.receipt.receiver_id.clone(),
                                     amount: *deposit,
                                     memo: None,
                                 };
-                                let predecessor_id = receipt.receipt.receipt.predecessor_id.clone();
-                                let transaction_id = transaction.transaction.transaction.hash;
-                                let receipt_id = receipt.receipt.receipt.receipt_id;
-                                let block_height = receipt.block_height;
-                                let block_timestamp_nanosec = receipt.block_timestamp_nanosec;
-                                self.0
-                                    .handle_transfer(
-                                        transfer,
-                                        EventContext {
-                                            transaction_id,
-                                            receipt_id,
-                                            block_height,
-                                            block_timestamp_nanosec,
-                                            predecessor_id,
-                                            contract_id: "near".parse().unwrap(),
-                                        },
-                                    )
-                                    .await;
+                                events.push((
+                                    ReceiptEvent::Ft(FtEvent::Transfer(transfer)),
+                                    get_native_context(
+                                        action_index,
+                                        NativeTransferKind::AccountDeletion,
+                                    ),
+                                ));
                             }
-                            ActionView::FunctionCall { deposit, .. } => {
-                                if *deposit > 1 {
-                                    let transfer = FtTransferEvent {
-                                        old_owner_id: receipt
-                                            .receipt
-                                            .receipt
-                                            .predecessor_id
-                                            .clone(),
-                                        new_owner_id: receipt.receipt.receipt.receiver_id.clone(),
-                                        amount: *deposit,
-                                        memo: None,
-                                    };
-                                    let predecessor_id =
-                                        receipt.receipt.receipt.predecessor_id.clone();
-                                    let transaction_id = transaction.transaction.transaction.hash;
-                                    let receipt_id = receipt.receipt.receipt.receipt_id;
-                                    let block_height = receipt.block_height;
-                                    let block_timestamp_nanosec = receipt.block_timestamp_nanosec;
-                                    self.0
-                                        .handle_transfer(
-                                            transfer,
-                                            EventContext {
-                                                transaction_id,
-                                                receipt_id,
-                                                block_height,
-                                                block_timestamp_nanosec,
-                                                predecessor_id,
-                                                contract_id: "near".parse().unwrap(),
-                                            },
+                        }
+                    }
+                }
+            } else {
+                for (action_index, action) in actions.iter().enumerate() {
+                    match action {
+                        ActionView::Transfer { deposit } => {
+                            let transfer = FtTransferEvent {
+                                old_owner_id: receipt.receipt.receipt.predecessor_id.clone(),
+                                new_owner_id: receipt.receipt.receipt.receiver_id.clone(),
+                                amount: *deposit,
+                                memo: None,
+                            };
+                            events.push((
+                                ReceiptEvent::Ft(FtEvent::Transfer(transfer)),
+                                get_native_context(action_index, NativeTransferKind::Transfer),
+                            ));
+                        }
+                        ActionView::FunctionCall {
+                            method_name,
+                            deposit,
+                            ..
+                        } => {
+                            if *deposit > 1 {
+                                let transfer = FtTransferEvent {
+                                    old_owner_id: receipt.receipt.receipt.predecessor_id.clone(),
+                                    new_owner_id: receipt.receipt.receipt.receiver_id.clone(),
+                                    amount: *deposit,
+                                    memo: None,
+                                };
+                                events.push((
+                                    ReceiptEvent::Ft(FtEvent::Transfer(transfer)),
+                                    EventContext {
+                                        method_name: Some(method_name.clone()),
+                                        deposit_category: Some(
+                                            self.options.deposit_categories.categorize(
+                                                &receipt.receipt.receipt.receiver_id,
+                                                method_name,
+                                            ),
+                                        ),
+                                        ..get_native_context(
+                                            action_index,
+                                            NativeTransferKind::FunctionCallDeposit,
                                         )
-                                        .await;
-                                }
+                                    },
+                                ));
                             }
-                            _ => {}
                         }
+                        // Actions of a meta transaction are executed in a separate receipt
+                        // from the delegating account, so its deposits are emitted when that
+                        // receipt is processed, with `relayer_id` set.
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        if let Some(transfer_call) = &transfer_call {
+            for (event, context) in events.iter_mut() {
+                if let ReceiptEvent::Ft(FtEvent::Transfer(transfer)) = event {
+                    if context.native_transfer.is_none()
+                        && transfer.new_owner_id == transfer_call.receiver_id
+                    {
+                        context.transfer_call = Some(transfer_call.clone());
+                    }
+                }
+            }
+        }
+        let resolve_transfer_args = match failure {
+            None => get_resolve_transfer_args(receipt),
+            Some(_) => None,
+        };
+        let transfer_event_id = resolve_transfer_args
+            .as_ref()
+            .and_then(|args| find_transfer_call_event_id(transaction, receipt, args));
+        if let Some(args) = &resolve_transfer_args {
+            if transfer_event_id.is_none() {
+                log::debug!(
+                    "Transfer resolved in {} not found in the transaction",
+                    receipt.receipt.receipt.receipt_id
+                );
+            }
+            for (event, context) in events.iter_mut() {
+                if let ReceiptEvent::Ft(FtEvent::Transfer(transfer)) = event {
+                    if context.native_transfer.is_none()
+                        && transfer.old_owner_id == args.receiver_id
+                        && transfer.new_owner_id == args.sender_id
+                    {
+                        context.refund_of.clone_from(&transfer_event_id);
+                    }
+                }
+            }
+        }
+
+        let mut token_verified = None;
+        let mut drop_unverified = false;
+        if let Some(verification) = &self.options.token_verification {
+            let verified = self
+                .token_verifier
+                .is_verified(verification, &receipt.receipt.receipt.receiver_id);
+            events.retain_mut(|(event, context)| {
+                if !matches!(event, ReceiptEvent::Ft(_)) || context.native_transfer.is_some() {
+                    return true;
+                }
+                context.token_verified = Some(verified);
+                verified || verification.policy == UnverifiedTokenPolicy::Flag
+            });
+            if failure.is_none() {
+                self.token_verifier
+                    .observe_successful_receipt(verification, receipt);
+            }
+            token_verified = Some(verified);
+            drop_unverified = !verified && verification.policy == UnverifiedTokenPolicy::Drop;
+        }
+
+        let wrap_event = if failure.is_none()
+            && receipt.receipt.receipt.receiver_id == self.options.wrap_contract_id
+        {
+            get_wrap_event(receipt, transaction, &events)
+        } else {
+            None
+        };
+        // Any contract can implement NEP-145, so storage events are verified
+        // like the token events of the contract
+        let storage_events = match failure {
+            None if !drop_unverified => get_storage_events(receipt, &events),
+            _ => Vec::new(),
+        };
+        let token_deployments = match failure {
+            None => get_token_deployments(receipt, &self.options.token_deployments),
+            Some(_) => Vec::new(),
+        };
+
+        let err = |source| FtIndexerError {
+            block_height: receipt.block_height,
+            receipt_id: Some(receipt.receipt.receipt.receipt_id),
+            source,
+        };
+        let mut discovered_tokens = Vec::new();
+        if failure.is_none() {
+            for (event, context) in &events {
+                if let ReceiptEvent::Ft(event) = event {
+                    if context.native_transfer.is_none() {
+                        if let Some(token) = self.token_registry.record(event, context) {
+                            discovered_tokens.push((token, context.clone()));
+                        }
+                    }
+                }
+            }
+        }
+
+        let filter = &self.options.filter;
+        events.retain(|(event, context)| event.passes(filter, context));
+        // An event that the handler rejects as invalid is skipped, and the
+        // rest of the receipt is still processed
+        let check = |result: Result<(), FtHandlerError>| match result {
+            Err(e) if e.action() == ErrorAction::Skip => {
+                log::warn!(
+                    "Skipped an event of receipt {}: {e}",
+                    receipt.receipt.receipt.receipt_id
+                );
+                Ok(())
+            }
+            result => result.map_err(err),
+        };
+        for (token, context) in discovered_tokens {
+            if self.options.filter.allows(&token.token_id, &[], None) {
+                check(self.handler.handle_token_discovered(token, context).await)?;
+            }
+        }
+        for (event, context) in events {
+            match &failure {
+                None => check(self.handle_event(event, context).await)?,
+                Some(error) => check(
+                    self.handle_reverted_event(event, context, error.clone())
+                        .await,
+                )?,
+            }
+        }
+
+        let wrap_event = wrap_event.filter(|wrap| {
+            self.options.filter.allows(
+                &receipt.receipt.receipt.receiver_id,
+                &[&wrap.account_id],
+                Some(wrap.amount),
+            )
+        });
+        if let Some(wrap_event) = wrap_event {
+            check(
+                self.handler
+                    .handle_near_wrap(wrap_event, base_context())
+                    .await,
+            )?;
+        }
+        for (action_index, token) in token_deployments {
+            if !self.options.filter.allows(&token.token_id, &[], None) {
+                continue;
+            }
+            let context = EventContext {
+                action_index: Some(action_index),
+                ..base_context()
+            };
+            check(self.handler.handle_token_deployed(token, context).await)?;
+        }
+        for (action_index, storage_event) in storage_events {
+            if !self.options.filter.allows(
+                &receipt.receipt.receipt.receiver_id,
+                &[&storage_event.account_id],
+                storage_event.amount,
+            ) {
+                continue;
+            }
+            let context = EventContext {
+                action_index: Some(action_index),
+                token_verified,
+                ..base_context()
+            };
+            check(self.handler.handle_storage(storage_event, context).await)?;
+        }
+        if self.options.emit_effective_transfers {
+            if let Some(args) = resolve_transfer_args {
+                if let Some(amount) = get_used_amount(receipt) {
+                    let transfer = FtEffectiveTransferEvent {
+                        old_owner_id: args.sender_id,
+                        new_owner_id: args.receiver_id,
+                        amount,
+                        transferred_amount: args.amount,
+                        transfer_event_id,
+                    };
+                    if self.options.filter.allows(
+                        &receipt.receipt.receipt.receiver_id,
+                        &[&transfer.old_owner_id, &transfer.new_owner_id],
+                        Some(transfer.amount),
+                    ) {
+                        check(
+                            self.handler
+                                .handle_effective_transfer(transfer, base_context())
+                                .await,
+                        )?;
                     }
                 }
             }
@@ -174,18 +775,345 @@ impl<T: FtEventHandler + Send + Sync + 'static> Indexer for FtIndexer<T> {
         Ok(())
     }
 
-    async fn process_block_end(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
-        self.0.flush_events(block.block.header.height).await;
+    /// Flushes the events of the block and saves the token registry.
+    /// [`Indexer::process_block_end`] calls it after every block.
+    pub async fn finish_block(&mut self, block_height: BlockHeight) -> Result<(), FtIndexerError> {
+        let err = |source| FtIndexerError {
+            block_height,
+            receipt_id: None,
+            source,
+        };
+        self.handler.flush_events(block_height).await.map_err(err)?;
+        self.token_registry
+            .finish_block(block_height)
+            .map_err(|e| err(e.into()))
+    }
+
+    async fn handle_event(
+        &mut self,
+        event: ReceiptEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        match event {
+            ReceiptEvent::Ft(FtEvent::Mint(mint)) => self.handler.handle_mint(mint, context).await,
+            ReceiptEvent::Ft(FtEvent::Transfer(transfer)) => {
+                self.handler.handle_transfer(transfer, context).await
+            }
+            ReceiptEvent::Ft(FtEvent::Burn(burn)) => self.handler.handle_burn(burn, context).await,
+            ReceiptEvent::MtMint(mint) => self.handler.handle_mt_mint(mint, context).await,
+            ReceiptEvent::MtTransfer(transfer) => {
+                self.handler.handle_mt_transfer(transfer, context).await
+            }
+            ReceiptEvent::MtBurn(burn) => self.handler.handle_mt_burn(burn, context).await,
+        }
+    }
+
+    async fn handle_reverted_event(
+        &mut self,
+        event: ReceiptEvent,
+        context: EventContext,
+        error: TxExecutionError,
+    ) -> Result<(), FtHandlerError> {
+        match event {
+            ReceiptEvent::Ft(FtEvent::Mint(mint)) => {
+                self.handler
+                    .handle_reverted_mint(mint, context, error)
+                    .await
+            }
+            ReceiptEvent::Ft(FtEvent::Transfer(transfer)) => {
+                self.handler
+                    .handle_reverted_transfer(transfer, context, error)
+                    .await
+            }
+            ReceiptEvent::Ft(FtEvent::Burn(burn)) => {
+                self.handler
+                    .handle_reverted_burn(burn, context, error)
+                    .await
+            }
+            ReceiptEvent::MtMint(_) | ReceiptEvent::MtTransfer(_) | ReceiptEvent::MtBurn(_) => {
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T: FtEventHandler + Send + Sync + 'static> Indexer for FtIndexer<T> {
+    type Error = FtIndexerError;
+
+    async fn on_receipt(
+        &mut self,
+        receipt: &TransactionReceipt,
+        transaction: &IncompleteTransaction,
+        _block: &StreamerMessage,
+    ) -> Result<(), Self::Error> {
+        self.process_receipt(receipt, transaction).await
+    }
+
+    async fn process_block_start(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
+        if let Some(verification) = &self.options.token_verification {
+            self.token_verifier.observe_block(verification, block);
+        }
+        let near: AccountId = "near".parse().unwrap();
+        for change in self.near_balance_changes.get_balance_changes(block) {
+            if !self
+                .options
+                .filter
+                .allows(&near, &[&change.account_id], None)
+            {
+                continue;
+            }
+            match self.handler.handle_near_balance_change(change).await {
+                Err(e) if e.action() == ErrorAction::Skip => {
+                    log::warn!(
+                        "Skipped a balance change in block {}: {e}",
+                        block.block.header.height
+                    );
+                }
+                result => result.map_err(|source| FtIndexerError {
+                    block_height: block.block.header.height,
+                    receipt_id: None,
+                    source,
+                })?,
+            }
+        }
         Ok(())
     }
+
+    async fn process_block_end(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
+        self.finish_block(block.block.header.height).await
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// If the receipt sends the balance of an account deleted earlier in the
+/// transaction to the beneficiary of `DeleteAccount`, returns the deleted
+/// account. Like gas refunds, these receipts come from `system`, but they are
+/// also signed by `system`, while gas refunds are signed by their receiver.
+fn get_deleted_account_id(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+) -> Option<AccountId> {
+    let ReceiptEnumView::Action { signer_id, .. } = &receipt.receipt.receipt.receipt else {
+        return None;
+    };
+    if receipt.receipt.receipt.predecessor_id != "system" || *signer_id != "system" {
+        return None;
+    }
+    let receipt_id = &receipt.receipt.receipt.receipt_id;
+    let beneficiary_id = &receipt.receipt.receipt.receiver_id;
+    transaction.receipts.values().flatten().find_map(|parent| {
+        let ReceiptEnumView::Action { actions, .. } = &parent.receipt.receipt.receipt else {
+            return None;
+        };
+        let deleted = parent.is_successful(false)
+            && parent
+                .receipt
+                .execution_outcome
+                .outcome
+                .receipt_ids
+                .contains(receipt_id)
+            && actions.iter().any(|action| {
+                matches!(action, ActionView::DeleteAccount { beneficiary_id: parent_beneficiary_id }
+                    if parent_beneficiary_id == beneficiary_id)
+            });
+        deleted.then(|| parent.receipt.receipt.receiver_id.clone())
+    })
+}
+
+/// If the transaction is a NEP-366 meta transaction, returns the relayer that
+/// submitted it
+fn get_relayer_id(transaction: &IncompleteTransaction) -> Option<AccountId> {
+    let signed_transaction = &transaction.transaction.transaction;
+    signed_transaction
+        .actions
+        .iter()
+        .any(|action| matches!(action, ActionView::Delegate { .. }))
+        .then(|| signed_transaction.signer_id.clone())
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct EventContext {
     pub transaction_id: CryptoHash,
     pub receipt_id: CryptoHash,
     pub block_height: BlockHeight,
+    #[serde(with = "serde_utils::dec_format")]
     pub block_timestamp_nanosec: u128,
     pub predecessor_id: AccountId,
     pub contract_id: AccountId,
+    /// Index of the log in the receipt's execution outcome, for events parsed from logs
+    pub log_index: Option<usize>,
+    /// Index of the event in the `data` array of the log
+    pub event_index: Option<usize>,
+    /// Index of the action in the receipt, for native NEAR transfers
+    pub action_index: Option<usize>,
+    /// Account that paid for gas of the meta transaction (NEP-366) that this
+    /// event is a part of. It's set for all receipts of the transaction. In
+    /// the receipt that executes the delegated actions, `predecessor_id` is
+    /// the account that signed the delegate action.
+    pub relayer_id: Option<AccountId>,
+    /// Where the NEAR came from, for native NEAR transfers (`contract_id` is `near`)
+    pub native_transfer: Option<NativeTransferKind>,
+    /// For refunds made in `ft_resolve_transfer`, [`EventContext::event_id`]
+    /// of the transfer made by `ft_transfer_call`
+    pub refund_of: Option<String>,
+    /// Method called in the receipt. For native NEAR transfers, the method
+    /// that the deposit was attached to.
+    pub method_name: Option<String>,
+    /// Arguments of `ft_transfer_call`, for the transfers that it made
+    pub transfer_call: Option<TransferCallContext>,
+    /// Whether the contract is a known token, if
+    /// [`FtIndexerOptions::token_verification`] is enabled. Set for NEP-141
+    /// and storage events, `None` for native NEAR and multi token events.
+    pub token_verified: Option<bool>,
+    /// What the NEAR attached to a function call pays for, see
+    /// [`FtIndexerOptions::deposit_categories`]. `None` for other events.
+    pub deposit_category: Option<DepositCategory>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum NativeTransferKind {
+    /// `Transfer` action
+    Transfer,
+    /// Deposit attached to a `FunctionCall` action
+    FunctionCallDeposit,
+    /// The whole balance of an account sent to the beneficiary of
+    /// `DeleteAccount`. It's emitted for the refund receipt from `system` that
+    /// sends it, so it's not emitted if the beneficiary doesn't exist and the
+    /// balance is burned.
+    AccountDeletion,
+}
+
+impl EventContext {
+    /// Identifier of the event that stays the same when it's indexed again,
+    /// derived from the receipt id and the position of the event in the receipt
+    pub fn event_id(&self) -> String {
+        match (self.log_index, self.event_index, self.action_index) {
+            (Some(log_index), Some(event_index), _) => {
+                log_event_id(&self.receipt_id, log_index, event_index)
+            }
+            (_, _, Some(action_index)) => format!("{}-action{action_index}", self.receipt_id),
+            _ => self.receipt_id.to_string(),
+        }
+    }
+}
+
+pub(crate) fn log_event_id(
+    receipt_id: &CryptoHash,
+    log_index: usize,
+    event_index: usize,
+) -> String {
+    format!("{receipt_id}-log{log_index}-{event_index}")
+}
+
+/// Error returned by [`FtEventHandler`] methods
+#[derive(Debug)]
+pub enum FtHandlerError {
+    Redis(redis::RedisError),
+    Io(std::io::Error),
+    /// The event can't be processed, but the following ones can
+    InvalidEvent(String),
+    Other(String),
+}
+
+/// What the runner should do after a handler failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// The failure is transient, processing the same block again may succeed
+    Retry,
+    /// Only this event is affected, indexing can continue. [`FtIndexer`]
+    /// logs these errors and passes the next events to the handler.
+    Skip,
+    /// Indexing can't continue
+    Abort,
+}
+
+impl FtHandlerError {
+    pub fn action(&self) -> ErrorAction {
+        match self {
+            FtHandlerError::Redis(e)
+                if e.is_io_error()
+                    || e.is_timeout()
+                    || e.is_connection_dropped()
+                    || e.is_connection_refusal() =>
+            {
+                ErrorAction::Retry
+            }
+            FtHandlerError::Redis(_) => ErrorAction::Abort,
+            FtHandlerError::Io(_) => ErrorAction::Retry,
+            FtHandlerError::InvalidEvent(_) => ErrorAction::Skip,
+            FtHandlerError::Other(_) => ErrorAction::Abort,
+        }
+    }
+}
+
+impl std::fmt::Display for FtHandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FtHandlerError::Redis(e) => write!(f, "Redis error: {e}"),
+            FtHandlerError::Io(e) => write!(f, "IO error: {e}"),
+            FtHandlerError::InvalidEvent(e) => write!(f, "Invalid event: {e}"),
+            FtHandlerError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for FtHandlerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FtHandlerError::Redis(e) => Some(e),
+            FtHandlerError::Io(e) => Some(e),
+            FtHandlerError::InvalidEvent(_) | FtHandlerError::Other(_) => None,
+        }
+    }
+}
+
+impl From<redis::RedisError> for FtHandlerError {
+    fn from(e: redis::RedisError) -> Self {
+        FtHandlerError::Redis(e)
+    }
+}
+
+impl From<std::io::Error> for FtHandlerError {
+    fn from(e: std::io::Error) -> Self {
+        FtHandlerError::Io(e)
+    }
+}
+
+/// Error returned by [`FtIndexer`], with the location where the handler failed
+#[derive(Debug)]
+pub struct FtIndexerError {
+    pub block_height: BlockHeight,
+    /// `None` if the failure happened outside of a receipt, while processing
+    /// state changes or flushing the block
+    pub receipt_id: Option<CryptoHash>,
+    pub source: FtHandlerError,
+}
+
+impl FtIndexerError {
+    pub fn action(&self) -> ErrorAction {
+        self.source.action()
+    }
+}
+
+impl std::fmt::Display for FtIndexerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.receipt_id {
+            Some(receipt_id) => write!(
+                f,
+                "Handler failed on receipt {receipt_id} in block {}: {}",
+                self.block_height, self.source
+            ),
+            None => write!(
+                f,
+                "Handler failed on block {}: {}",
+                self.block_height, self.source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FtIndexerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
 }
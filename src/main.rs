@@ -1,14 +1,76 @@
 #[cfg(test)]
 mod tests;
 
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ft_indexer::balance_changes::NearBalanceChanges;
+use ft_indexer::deposit_category::DepositCategoryRules;
+use ft_indexer::discovery::{TokenDeploymentOptions, TokenRegistry};
+use ft_indexer::filter::FilterConfig;
 use ft_indexer::redis_handler;
+use ft_indexer::spill_queue::SpillQueue;
+use ft_indexer::verification::{
+    load_token_registry, TokenVerificationOptions, UnverifiedTokenPolicy,
+};
+use ft_indexer::{ErrorAction, FtEventHandler, FtIndexer, FtIndexerError, FtIndexerOptions};
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use inindexer::near_indexer_primitives::StreamerMessage;
 use inindexer::neardata::NeardataProvider;
 use inindexer::{
-    run_indexer, AutoContinue, BlockRange, IndexerOptions, PreprocessTransactionsSettings,
+    run_indexer, AutoContinue, BlockRange, IncompleteTransaction, Indexer, IndexerOptions,
+    PreprocessTransactionsSettings, TransactionReceipt,
 };
 use redis::aio::ConnectionManager;
 use redis_handler::PushToRedisStream;
 
+/// How many times the indexer is restarted from a block that failed with an
+/// error that may go away, before giving up
+const MAX_RETRIES: u32 = 5;
+/// Delay before the first restart, doubled after every failure of the block
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Passes blocks to [`FtIndexer`] and remembers what to do about the last
+/// error it returned, and the block where it happened
+struct RecoveringIndexer<T: FtEventHandler + Send + Sync + 'static> {
+    indexer: FtIndexer<T>,
+    failure: Option<(ErrorAction, BlockHeight)>,
+}
+
+impl<T: FtEventHandler + Send + Sync + 'static> RecoveringIndexer<T> {
+    fn record(&mut self, result: Result<(), FtIndexerError>) -> Result<(), FtIndexerError> {
+        if let Err(e) = &result {
+            self.failure = Some((e.action(), e.block_height));
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl<T: FtEventHandler + Send + Sync + 'static> Indexer for RecoveringIndexer<T> {
+    type Error = FtIndexerError;
+
+    async fn process_block_start(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
+        let result = self.indexer.process_block_start(block).await;
+        self.record(result)
+    }
+
+    async fn on_receipt(
+        &mut self,
+        receipt: &TransactionReceipt,
+        transaction: &IncompleteTransaction,
+        block: &StreamerMessage,
+    ) -> Result<(), Self::Error> {
+        let result = self.indexer.on_receipt(receipt, transaction, block).await;
+        self.record(result)
+    }
+
+    async fn process_block_end(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
+        let result = self.indexer.process_block_end(block).await;
+        self.record(result)
+    }
+}
+
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
@@ -24,40 +86,138 @@ async fn main() {
     .unwrap();
     let connection = ConnectionManager::new(client).await.unwrap();
 
-    let mut indexer = ft_indexer::FtIndexer(PushToRedisStream::new(connection, 10_000).await);
-
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: if cfg!(debug_assertions) { 0 } else { 100 },
-                postfetch_blocks: 0,
-            }),
-            ..IndexerOptions::default_with_range(if std::env::args().len() > 1 {
-                // For debugging
-                let msg = "Usage: `indexer` or `indexer [start-block] [end-block]`";
-                BlockRange::Range {
-                    start_inclusive: std::env::args()
-                        .nth(1)
-                        .expect(msg)
-                        .replace(['_', ',', ' ', '.'], "")
-                        .parse()
-                        .expect(msg),
-                    end_exclusive: Some(
-                        std::env::args()
-                            .nth(2)
-                            .expect(msg)
-                            .replace(['_', ',', ' ', '.'], "")
-                            .parse()
-                            .expect(msg),
-                    ),
+    let mut handler = PushToRedisStream::new(connection, 10_000).await;
+    if let Ok(spill_dir) = std::env::var("SPILL_QUEUE_DIR") {
+        handler = handler
+            .with_spill_queue(SpillQueue::open(spill_dir).expect("Failed to open spill queue"));
+    }
+    let filter_config = std::env::var("FILTER_CONFIG_FILE")
+        .map(|path| FilterConfig::load(path).expect("Failed to load filter config"))
+        .unwrap_or_default();
+    for (stream, filter) in filter_config.streams {
+        handler = handler.with_stream_filter(stream, filter);
+    }
+    let last_processed_block = handler
+        .last_processed_block()
+        .await
+        .expect("Failed to read the last processed block");
+    let mut ft_indexer = FtIndexer::with_options(
+        handler,
+        FtIndexerOptions {
+            index_reverted_events: std::env::var("INDEX_REVERTED_EVENTS").is_ok(),
+            emit_effective_transfers: std::env::var("EMIT_EFFECTIVE_TRANSFERS").is_ok(),
+            token_verification: std::env::var("TOKEN_REGISTRY_FILE").ok().map(|path| {
+                TokenVerificationOptions {
+                    policy: if std::env::var("DROP_UNVERIFIED_TOKENS").is_ok() {
+                        UnverifiedTokenPolicy::Drop
+                    } else {
+                        UnverifiedTokenPolicy::Flag
+                    },
+                    known_tokens: load_token_registry(path).expect("Failed to load token registry"),
+                    ..Default::default()
                 }
-            } else {
-                BlockRange::AutoContinue(AutoContinue::default())
-            })
+            }),
+            deposit_categories: std::env::var("DEPOSIT_CATEGORY_RULES_FILE")
+                .map(|path| {
+                    DepositCategoryRules::load(path).expect("Failed to load deposit category rules")
+                })
+                .unwrap_or_default(),
+            filter: filter_config.indexer,
+            token_deployments: TokenDeploymentOptions {
+                known_code_hashes: std::env::var("FT_CODE_HASHES")
+                    .map(|hashes| {
+                        hashes
+                            .split(',')
+                            .map(|hash| hash.trim().parse().expect("Invalid code hash"))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                ..Default::default()
+            },
+            ..Default::default()
         },
-    )
-    .await
-    .expect("Indexer run failed");
+    );
+    if let Ok(path) = std::env::var("DISCOVERED_TOKENS_FILE") {
+        ft_indexer = ft_indexer.with_token_registry(
+            // New tokens are saved right away, only counters wait for the interval
+            TokenRegistry::open(path, 100).expect("Failed to open discovered tokens registry"),
+        );
+    }
+    if let Ok(capacity) = std::env::var("NEAR_BALANCE_CACHE_SIZE") {
+        ft_indexer = ft_indexer.with_near_balance_changes(NearBalanceChanges::with_capacity(
+            capacity.parse().expect("Invalid $NEAR_BALANCE_CACHE_SIZE"),
+        ));
+    }
+    let mut indexer = RecoveringIndexer {
+        indexer: ft_indexer,
+        failure: None,
+    };
+
+    let (mut start_inclusive, end_exclusive): (Option<BlockHeight>, Option<BlockHeight>) =
+        if std::env::args().len() > 1 {
+            // For debugging
+            let msg = "Usage: `indexer` or `indexer [start-block] [end-block]`";
+            let arg = |n| {
+                std::env::args()
+                    .nth(n)
+                    .expect(msg)
+                    .replace(['_', ',', ' ', '.'], "")
+                    .parse()
+                    .expect(msg)
+            };
+            (Some(arg(1)), Some(arg(2)))
+        } else {
+            // Resume from the checkpoint written together with the events
+            (last_processed_block.map(|block| block + 1), None)
+        };
+    let mut retries = 0;
+    let mut last_failed_block = None;
+    loop {
+        let result = run_indexer(
+            &mut indexer,
+            NeardataProvider::mainnet(),
+            IndexerOptions {
+                preprocess_transactions: Some(PreprocessTransactionsSettings {
+                    prefetch_blocks: if cfg!(debug_assertions) { 0 } else { 100 },
+                    postfetch_blocks: 0,
+                }),
+                ..IndexerOptions::default_with_range(match start_inclusive {
+                    Some(start_inclusive) => BlockRange::Range {
+                        start_inclusive,
+                        end_exclusive,
+                    },
+                    None => BlockRange::AutoContinue(AutoContinue::default()),
+                })
+            },
+        )
+        .await;
+        let Err(e) = result else {
+            break;
+        };
+        let failure = indexer.failure.take();
+        if let Some((_, block_height)) = failure {
+            if last_failed_block != Some(block_height) {
+                retries = 0;
+                last_failed_block = Some(block_height);
+            }
+        }
+        match failure {
+            Some((ErrorAction::Retry, block_height)) if retries < MAX_RETRIES => {
+                retries += 1;
+                let backoff = RETRY_BACKOFF * 2u32.pow(retries - 1);
+                log::warn!(
+                    "Indexer failed on block {block_height}, retrying in {backoff:?}: {e:?}"
+                );
+                tokio::time::sleep(backoff).await;
+                start_inclusive = Some(block_height);
+            }
+            Some((ErrorAction::Skip, block_height)) => {
+                log::error!("Indexer failed on block {block_height}, skipping it: {e:?}");
+                start_inclusive = Some(block_height + 1);
+            }
+            _ => panic!("Indexer run failed: {e:?}"),
+        }
+        // The events of the failed block that weren't flushed are added again
+        indexer.indexer.handler.discard_pending();
+    }
 }
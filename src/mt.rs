@@ -0,0 +1,96 @@
+//! NEP-245 multi token events (`mt_mint`, `mt_transfer`, `mt_burn`)
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::serde_utils::vec_dec_format;
+
+const EVENT_JSON_PREFIX: &str = "EVENT_JSON:";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MtMintEvent {
+    pub owner_id: AccountId,
+    pub token_ids: Vec<String>,
+    #[serde(with = "vec_dec_format")]
+    pub amounts: Vec<u128>,
+    pub memo: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MtTransferEvent {
+    pub old_owner_id: AccountId,
+    pub new_owner_id: AccountId,
+    pub token_ids: Vec<String>,
+    #[serde(with = "vec_dec_format")]
+    pub amounts: Vec<u128>,
+    pub authorized_id: Option<AccountId>,
+    pub memo: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MtBurnEvent {
+    pub owner_id: AccountId,
+    pub token_ids: Vec<String>,
+    #[serde(with = "vec_dec_format")]
+    pub amounts: Vec<u128>,
+    pub authorized_id: Option<AccountId>,
+    pub memo: Option<String>,
+}
+
+/// Events of one NEP-245 log
+#[derive(Debug, Clone, PartialEq)]
+pub enum MtEventLog {
+    Mint(Vec<MtMintEvent>),
+    Transfer(Vec<MtTransferEvent>),
+    Burn(Vec<MtBurnEvent>),
+}
+
+#[derive(Deserialize)]
+struct RawEventLog {
+    standard: String,
+    version: String,
+    event: String,
+    data: serde_json::Value,
+}
+
+impl MtEventLog {
+    /// Parses an `EVENT_JSON:` log. Returns `None` if the log is not a valid
+    /// NEP-245 mint, transfer, or burn event, including when the lengths of
+    /// `token_ids` and `amounts` don't match in any of the events.
+    pub fn parse(log: &str) -> Option<Self> {
+        let raw: RawEventLog =
+            serde_json::from_str(log.strip_prefix(EVENT_JSON_PREFIX)?.trim()).ok()?;
+        if raw.standard != "nep245" || raw.version != "1.0.0" {
+            return None;
+        }
+        let log = match raw.event.as_str() {
+            "mt_mint" => MtEventLog::Mint(parse_data(raw.data, |e: &MtMintEvent| {
+                amounts_match(&e.token_ids, &e.amounts)
+            })?),
+            "mt_transfer" => MtEventLog::Transfer(parse_data(raw.data, |e: &MtTransferEvent| {
+                amounts_match(&e.token_ids, &e.amounts)
+            })?),
+            "mt_burn" => MtEventLog::Burn(parse_data(raw.data, |e: &MtBurnEvent| {
+                amounts_match(&e.token_ids, &e.amounts)
+            })?),
+            _ => return None,
+        };
+        Some(log)
+    }
+}
+
+fn parse_data<T: DeserializeOwned>(
+    data: serde_json::Value,
+    validate: impl Fn(&T) -> bool,
+) -> Option<Vec<T>> {
+    let events: Vec<T> = serde_json::from_value(data).ok()?;
+    if events.is_empty() || !events.iter().all(validate) {
+        return None;
+    }
+    Some(events)
+}
+
+fn amounts_match(token_ids: &[String], amounts: &[u128]) -> bool {
+    !token_ids.is_empty() && token_ids.len() == amounts.len()
+}
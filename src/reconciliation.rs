@@ -0,0 +1,217 @@
+//! Comparison of the balances kept by a [`BalanceTracker`](crate::balance_tracker::BalanceTracker)
+//! with `ft_balance_of` of the token contracts, to find events that the
+//! indexer doesn't understand
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use serde::{Deserialize, Serialize};
+
+use crate::balance_tracker::{BalanceReader, NEAR_TOKEN_ID};
+use crate::serde_utils::{dec_format, signed_dec_format};
+
+/// Minimal NEAR JSON-RPC client for view calls
+#[derive(Clone, Debug)]
+pub struct RpcClient {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    result: Option<CallResult>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct CallResult {
+    result: Option<Vec<u8>>,
+    error: Option<String>,
+}
+
+impl RpcClient {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+        }
+    }
+
+    /// Balance of `account_id` in `token_id` at the end of `block_height`,
+    /// which requires an archival node for old blocks
+    pub async fn ft_balance_of(
+        &self,
+        token_id: &AccountId,
+        account_id: &AccountId,
+        block_height: BlockHeight,
+    ) -> Result<u128, String> {
+        let args = serde_json::json!({ "account_id": account_id }).to_string();
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": "ft-indexer",
+            "method": "query",
+            "params": {
+                "request_type": "call_function",
+                "block_id": block_height,
+                "account_id": token_id,
+                "method_name": "ft_balance_of",
+                "args_base64": BASE64.encode(args),
+            },
+        });
+        let response: RpcResponse = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("RPC request to {} failed: {e}", self.url))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid RPC response from {}: {e}", self.url))?;
+        if let Some(error) = response.error {
+            return Err(format!("ft_balance_of on {token_id} failed: {error}"));
+        }
+        let result = response
+            .result
+            .ok_or_else(|| format!("No result in RPC response from {}", self.url))?;
+        if let Some(error) = result.error {
+            return Err(format!("ft_balance_of on {token_id} failed: {error}"));
+        }
+        let balance: String = serde_json::from_slice(&result.result.unwrap_or_default())
+            .map_err(|e| format!("Invalid ft_balance_of result of {token_id}: {e}"))?;
+        balance
+            .parse()
+            .map_err(|e| format!("Invalid ft_balance_of result of {token_id}: {e}"))
+    }
+}
+
+/// A balance that differs from `ft_balance_of`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BalanceMismatch {
+    pub token_id: AccountId,
+    pub account_id: AccountId,
+    #[serde(with = "signed_dec_format")]
+    pub indexed_balance: i128,
+    #[serde(with = "dec_format")]
+    pub rpc_balance: u128,
+    /// Last block where the balances were checked and matched. The event that
+    /// was missed or misread is after it, up to
+    /// [`ReconciliationReport::block_height`].
+    pub last_matching_block: Option<BlockHeight>,
+    /// First block where the balances were checked and didn't match
+    pub first_mismatching_block: BlockHeight,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ReconciliationReport {
+    pub block_height: BlockHeight,
+    pub checked: usize,
+    pub mismatches: Vec<BalanceMismatch>,
+}
+
+/// Checks a different sample of the tracked balances on every run, going
+/// through all of them in order. Native NEAR balances are not checked.
+pub struct Reconciler {
+    reader: BalanceReader,
+    rpc: RpcClient,
+    sample_size: usize,
+    /// Token id and account id of the last checked balance. The next sample
+    /// starts after it.
+    cursor: Option<(AccountId, AccountId)>,
+    last_matching_blocks: HashMap<(AccountId, AccountId), BlockHeight>,
+    first_mismatching_blocks: HashMap<(AccountId, AccountId), BlockHeight>,
+}
+
+impl Reconciler {
+    pub fn new(reader: BalanceReader, rpc: RpcClient, sample_size: usize) -> Self {
+        Self {
+            reader,
+            rpc,
+            sample_size,
+            cursor: None,
+            last_matching_blocks: HashMap::new(),
+            first_mismatching_blocks: HashMap::new(),
+        }
+    }
+
+    /// Checks the next sample of balances. Returns `None` if no block has
+    /// been processed yet. If a check fails, the same sample is checked again
+    /// on the next run.
+    pub async fn run_once(&mut self) -> Result<Option<ReconciliationReport>, String> {
+        let is_ft = |token_id: &AccountId| token_id.as_str() != NEAR_TOKEN_ID;
+        let after = self
+            .cursor
+            .as_ref()
+            .map(|(token_id, account_id)| (token_id, account_id));
+        let (mut block_height, mut sample) =
+            self.reader.balances_after(after, self.sample_size, is_ft);
+        if sample.is_empty() && after.is_some() {
+            // Start over after the last balance
+            (block_height, sample) = self.reader.balances_after(None, self.sample_size, is_ft);
+        }
+        let Some(block_height) = block_height else {
+            return Ok(None);
+        };
+
+        let mut mismatches = Vec::new();
+        for (token_id, account_id, indexed_balance) in &sample {
+            let rpc_balance = self
+                .rpc
+                .ft_balance_of(token_id, account_id, block_height)
+                .await?;
+            let key = (token_id.clone(), account_id.clone());
+            if u128::try_from(*indexed_balance).ok() == Some(rpc_balance) {
+                self.last_matching_blocks.insert(key.clone(), block_height);
+                self.first_mismatching_blocks.remove(&key);
+                continue;
+            }
+            let first_mismatching_block = *self
+                .first_mismatching_blocks
+                .entry(key.clone())
+                .or_insert(block_height);
+            let mismatch = BalanceMismatch {
+                token_id: token_id.clone(),
+                account_id: account_id.clone(),
+                indexed_balance: *indexed_balance,
+                rpc_balance,
+                last_matching_block: self.last_matching_blocks.get(&key).copied(),
+                first_mismatching_block,
+            };
+            log::warn!(
+                "Balance of {account_id} in {token_id} is {indexed_balance}, but ft_balance_of returned {rpc_balance} at block {block_height}, diverged after block {:?}",
+                mismatch.last_matching_block
+            );
+            mismatches.push(mismatch);
+        }
+        if let Some((token_id, account_id, _)) = sample.last() {
+            self.cursor = Some((token_id.clone(), account_id.clone()));
+        }
+        Ok(Some(ReconciliationReport {
+            block_height,
+            checked: sample.len(),
+            mismatches,
+        }))
+    }
+
+    /// Checks a sample every `interval` and passes every report to
+    /// `on_report`. Failed runs are logged and retried on the next tick.
+    pub async fn run(
+        mut self,
+        interval: Duration,
+        mut on_report: impl FnMut(ReconciliationReport) + Send,
+    ) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match self.run_once().await {
+                Ok(Some(report)) => on_report(report),
+                Ok(None) => {}
+                Err(e) => log::error!("Reconciliation failed: {e}"),
+            }
+        }
+    }
+}
@@ -1,35 +1,392 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::Duration;
+
 use async_trait::async_trait;
-use inevents_redis::RedisEventStream;
-use inindexer::{near_indexer_primitives::types::BlockHeight, near_utils};
+use inindexer::near_indexer_primitives::near_primitives::errors::TxExecutionError;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_utils;
 use intear_events::events::ft::{
     ft_burn::FtBurnEvent, ft_mint::FtMintEvent, ft_transfer::FtTransferEvent,
 };
 use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::balance_changes::NearBalanceChange;
+use crate::discovery::{FtTokenDeployedEvent, FtTokenDiscoveredEvent};
+use crate::filter::EventFilter;
+use crate::mt::{MtBurnEvent, MtMintEvent, MtTransferEvent};
+use crate::spill_queue::SpillQueue;
+use crate::storage::FtStorageEvent;
+use crate::supply::SupplyChange;
+use crate::transfer_call::FtEffectiveTransferEvent;
+use crate::wrap::NearWrapEvent;
+use crate::{EventContext, FtEventHandler, FtHandlerError};
+
+pub const MT_MINT_STREAM: &str = "mt_mint";
+pub const MT_TRANSFER_STREAM: &str = "mt_transfer";
+pub const MT_BURN_STREAM: &str = "mt_burn";
+pub const NEAR_BALANCE_CHANGE_STREAM: &str = "near_balance_change";
+pub const FT_MINT_REVERTED_STREAM: &str = "ft_mint_reverted";
+pub const FT_TRANSFER_REVERTED_STREAM: &str = "ft_transfer_reverted";
+pub const FT_BURN_REVERTED_STREAM: &str = "ft_burn_reverted";
+pub const FT_TRANSFER_EFFECTIVE_STREAM: &str = "ft_transfer_effective";
+pub const NEAR_WRAP_STREAM: &str = "near_wrap";
+pub const FT_STORAGE_STREAM: &str = "ft_storage";
+pub const FT_SUPPLY_STREAM: &str = "ft_supply";
+pub const FT_TOKEN_DISCOVERED_STREAM: &str = "ft_token_discovered";
+pub const FT_TOKEN_DEPLOYED_STREAM: &str = "ft_token_deployed";
+
+/// Format of streams that don't have an event type in `intear_events`: the
+/// event fields with the [`EventContext`] fields next to them
+#[derive(Serialize)]
+struct EventWithContext<'a, E: Serialize> {
+    #[serde(flatten)]
+    event: &'a E,
+    #[serde(flatten)]
+    context: &'a EventContext,
+}
+
+/// Format of the `*_reverted` streams
+#[derive(Serialize)]
+struct RevertedEvent<'a, E: Serialize> {
+    #[serde(flatten)]
+    event: &'a E,
+    #[serde(flatten)]
+    context: &'a EventContext,
+    error: &'a TxExecutionError,
+}
+
+/// A serialized event waiting to be added to a stream
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamEntry {
+    pub stream: String,
+    pub event: String,
+    /// [`EventContext::event_id`], added to the entry next to the event
+    #[serde(default)]
+    pub event_id: String,
+    /// [`EventContext`] of events that are stored in `intear_events` format,
+    /// which doesn't include all of its fields
+    #[serde(default)]
+    pub context: Option<String>,
+}
+
+/// How many times, and how often, a failed flush is retried before the block
+/// is given up on (or spilled to disk, if a spill queue is configured)
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Key of the last block written to Redis
+pub const DEFAULT_CHECKPOINT_KEY: &str = "ft_indexer_last_block";
 
-use crate::{EventContext, FtEventHandler};
+/// Entry ids are `{block_height}-{index}`, so consumers can read the block
+/// height of every entry from its id.
+///
+/// Redis doesn't roll back a script that fails halfway, so every stream is
+/// checked before the first `XADD`: if one already has an entry of this or a
+/// later block, none of the block's entries could be added after it, so the
+/// block is treated as already written and the checkpoint is set to the
+/// latest block in the streams.
+///
+/// KEYS: checkpoint key, then every stream the block writes to.
+/// ARGV: block height, max stream size, then (stream key index, entry id,
+/// event, event id, context or an empty string) for each event.
+static WRITE_BLOCK_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+local last = redis.call('GET', KEYS[1])
+if last and tonumber(last) >= tonumber(ARGV[1]) then
+    return 0
+end
+local written = nil
+for i = 2, #KEYS do
+    local top = redis.call('XREVRANGE', KEYS[i], '+', '-', 'COUNT', 1)[1]
+    if top then
+        local height = tonumber(string.match(top[1], '^(%d+)-'))
+        if height >= tonumber(ARGV[1]) and (not written or height > written) then
+            written = height
+        end
+    end
+end
+if written then
+    redis.call('SET', KEYS[1], written)
+    return 0
+end
+for i = 3, #ARGV, 5 do
+    local fields = {'event', ARGV[i + 2], 'event_id', ARGV[i + 3]}
+    if ARGV[i + 4] ~= '' then
+        table.insert(fields, 'context')
+        table.insert(fields, ARGV[i + 4])
+    end
+    redis.call('XADD', KEYS[tonumber(ARGV[i])], 'MAXLEN', '~', ARGV[2], ARGV[i + 1], unpack(fields))
+end
+redis.call('SET', KEYS[1], ARGV[1])
+return 1
+",
+    )
+});
 
 pub struct PushToRedisStream {
-    mint_stream: RedisEventStream<FtMintEvent>,
-    transfer_stream: RedisEventStream<FtTransferEvent>,
-    burn_stream: RedisEventStream<FtBurnEvent>,
+    connection: ConnectionManager,
+    checkpoint_key: String,
+    pending: Vec<StreamEntry>,
     max_stream_size: usize,
+    retry_policy: RetryPolicy,
+    spill_queue: Option<SpillQueue<Vec<StreamEntry>>>,
+    stream_filters: HashMap<String, EventFilter>,
 }
 
 impl PushToRedisStream {
     pub async fn new(connection: ConnectionManager, max_stream_size: usize) -> Self {
         Self {
-            mint_stream: RedisEventStream::new(connection.clone(), FtMintEvent::ID),
-            transfer_stream: RedisEventStream::new(connection.clone(), FtTransferEvent::ID),
-            burn_stream: RedisEventStream::new(connection.clone(), FtBurnEvent::ID),
+            connection,
+            checkpoint_key: DEFAULT_CHECKPOINT_KEY.to_owned(),
+            pending: Vec::new(),
             max_stream_size,
+            retry_policy: RetryPolicy::default(),
+            spill_queue: None,
+            stream_filters: HashMap::new(),
+        }
+    }
+
+    pub fn with_checkpoint_key(mut self, checkpoint_key: impl Into<String>) -> Self {
+        self.checkpoint_key = checkpoint_key.into();
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Blocks that couldn't be written after all retries are saved to this
+    /// queue instead of failing the indexer, and written in order once Redis
+    /// is reachable again.
+    pub fn with_spill_queue(mut self, spill_queue: SpillQueue<Vec<StreamEntry>>) -> Self {
+        self.spill_queue = Some(spill_queue);
+        self
+    }
+
+    /// Only events that pass `filter` are added to `stream`
+    pub fn with_stream_filter(mut self, stream: impl Into<String>, filter: EventFilter) -> Self {
+        self.stream_filters.insert(stream.into(), filter);
+        self
+    }
+
+    fn passes_filter(
+        &self,
+        stream: &str,
+        contract_id: &AccountId,
+        accounts: &[&AccountId],
+        amount: Option<u128>,
+    ) -> bool {
+        match self.stream_filters.get(stream) {
+            Some(filter) => filter.allows(contract_id, accounts, amount),
+            None => true,
+        }
+    }
+
+    /// Drops the events added since the last flush, before the block they
+    /// belong to is processed again
+    pub fn discard_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    fn add_event(
+        &mut self,
+        stream: &str,
+        event: &impl Serialize,
+        event_id: String,
+    ) -> Result<(), FtHandlerError> {
+        let event = serde_json::to_string(event)
+            .map_err(|e| FtHandlerError::InvalidEvent(e.to_string()))?;
+        self.pending.push(StreamEntry {
+            stream: stream.to_owned(),
+            event,
+            event_id,
+            context: None,
+        });
+        Ok(())
+    }
+
+    /// Adds an event in `intear_events` format, with the full context in a
+    /// separate field
+    fn add_event_and_context(
+        &mut self,
+        stream: &str,
+        event: &impl Serialize,
+        context: &EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.add_event(stream, event, context.event_id())?;
+        let context = serde_json::to_string(context)
+            .map_err(|e| FtHandlerError::InvalidEvent(e.to_string()))?;
+        if let Some(entry) = self.pending.last_mut() {
+            entry.context = Some(context);
+        }
+        Ok(())
+    }
+
+    fn add_event_with_context(
+        &mut self,
+        stream: &str,
+        event: &impl Serialize,
+        context: &EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.add_event(
+            stream,
+            &EventWithContext { event, context },
+            context.event_id(),
+        )
+    }
+
+    fn add_reverted_event(
+        &mut self,
+        stream: &str,
+        event: &impl Serialize,
+        context: &EventContext,
+        error: &TxExecutionError,
+    ) -> Result<(), FtHandlerError> {
+        self.add_event(
+            stream,
+            &RevertedEvent {
+                event,
+                context,
+                error,
+            },
+            context.event_id(),
+        )
+    }
+
+    /// Height of the last block whose events are in Redis or waiting in the
+    /// spill queue. Indexing should resume from the block after it.
+    pub async fn last_processed_block(&mut self) -> Result<Option<BlockHeight>, FtHandlerError> {
+        let written: Option<BlockHeight> = redis::cmd("GET")
+            .arg(&self.checkpoint_key)
+            .query_async(&mut self.connection)
+            .await?;
+        let spilled = self
+            .spill_queue
+            .as_ref()
+            .and_then(|queue| queue.last_block_height());
+        Ok(written.max(spilled))
+    }
+
+    /// Adds all events of the block and moves the checkpoint in one atomic
+    /// script. Blocks at or below the checkpoint are skipped, so a block that
+    /// is written again after a crash or a retry is never duplicated.
+    async fn write_block(
+        &mut self,
+        block_height: BlockHeight,
+        entries: &[StreamEntry],
+    ) -> Result<(), redis::RedisError> {
+        let mut invocation = WRITE_BLOCK_SCRIPT.prepare_invoke();
+        invocation.key(&self.checkpoint_key);
+        let mut streams = Vec::<&str>::new();
+        let mut indices = HashMap::<&str, usize>::new();
+        invocation.arg(block_height).arg(self.max_stream_size);
+        for entry in entries {
+            let key_index = match streams.iter().position(|s| *s == entry.stream) {
+                Some(position) => position + 2,
+                None => {
+                    invocation.key(&entry.stream);
+                    streams.push(&entry.stream);
+                    streams.len() + 1
+                }
+            };
+            let index = indices.entry(entry.stream.as_str()).or_default();
+            invocation
+                .arg(key_index)
+                .arg(format!("{block_height}-{index}"))
+                .arg(&entry.event)
+                .arg(&entry.event_id)
+                .arg(entry.context.as_deref().unwrap_or_default());
+            *index += 1;
+        }
+        let written: bool = invocation.invoke_async(&mut self.connection).await?;
+        if !written {
+            log::info!("Block {block_height} is already in Redis, skipping");
         }
+        Ok(())
+    }
+
+    async fn write_block_with_retries(
+        &mut self,
+        block_height: BlockHeight,
+        entries: &[StreamEntry],
+    ) -> Result<(), redis::RedisError> {
+        let mut backoff = self.retry_policy.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match self.write_block(block_height, entries).await {
+                Ok(()) => return Ok(()),
+                Err(e) if retries < self.retry_policy.max_retries => {
+                    log::warn!(
+                        "Failed to write block {block_height} to Redis, retrying in {backoff:?}: {e}"
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.retry_policy.max_backoff);
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes spilled blocks to Redis, oldest first, until the queue is empty
+    /// or a write fails
+    async fn drain_spill_queue(&mut self) -> Result<(), FtHandlerError> {
+        while let Some((block_height, entries)) = self
+            .spill_queue
+            .as_ref()
+            .map(|queue| queue.front())
+            .transpose()?
+            .flatten()
+        {
+            if let Err(e) = self.write_block(block_height, &entries).await {
+                log::warn!("Redis is still unavailable, keeping spilled blocks: {e}");
+                return Ok(());
+            }
+            if let Some(queue) = self.spill_queue.as_mut() {
+                queue.pop_front()?;
+                if queue.is_empty() {
+                    log::info!("Spill queue drained, last block {block_height}");
+                }
+            }
+        }
+        Ok(())
     }
 }
 
 #[async_trait]
 impl FtEventHandler for PushToRedisStream {
-    async fn handle_mint(&mut self, mint: near_utils::FtMintEvent, context: EventContext) {
-        self.mint_stream.add_event(FtMintEvent {
+    async fn handle_mint(
+        &mut self,
+        mint: near_utils::FtMintEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            FtMintEvent::ID,
+            &context.contract_id,
+            &[&mint.owner_id],
+            Some(mint.amount),
+        ) {
+            return Ok(());
+        }
+        let event = FtMintEvent {
             owner_id: mint.owner_id,
             amount: mint.amount,
             memo: mint.memo,
@@ -37,16 +394,25 @@ impl FtEventHandler for PushToRedisStream {
             receipt_id: context.receipt_id,
             block_height: context.block_height,
             block_timestamp_nanosec: context.block_timestamp_nanosec,
-            token_id: context.contract_id,
-        });
+            token_id: context.contract_id.clone(),
+        };
+        self.add_event_and_context(FtMintEvent::ID, &event, &context)
     }
 
     async fn handle_transfer(
         &mut self,
         transfer: near_utils::FtTransferEvent,
         context: EventContext,
-    ) {
-        self.transfer_stream.add_event(FtTransferEvent {
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            FtTransferEvent::ID,
+            &context.contract_id,
+            &[&transfer.old_owner_id, &transfer.new_owner_id],
+            Some(transfer.amount),
+        ) {
+            return Ok(());
+        }
+        let event = FtTransferEvent {
             old_owner_id: transfer.old_owner_id,
             new_owner_id: transfer.new_owner_id,
             amount: transfer.amount,
@@ -55,12 +421,25 @@ impl FtEventHandler for PushToRedisStream {
             receipt_id: context.receipt_id,
             block_height: context.block_height,
             block_timestamp_nanosec: context.block_timestamp_nanosec,
-            token_id: context.contract_id,
-        });
+            token_id: context.contract_id.clone(),
+        };
+        self.add_event_and_context(FtTransferEvent::ID, &event, &context)
     }
 
-    async fn handle_burn(&mut self, burn: near_utils::FtBurnEvent, context: EventContext) {
-        self.burn_stream.add_event(FtBurnEvent {
+    async fn handle_burn(
+        &mut self,
+        burn: near_utils::FtBurnEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            FtBurnEvent::ID,
+            &context.contract_id,
+            &[&burn.owner_id],
+            Some(burn.amount),
+        ) {
+            return Ok(());
+        }
+        let event = FtBurnEvent {
             owner_id: burn.owner_id,
             amount: burn.amount,
             memo: burn.memo,
@@ -68,22 +447,236 @@ impl FtEventHandler for PushToRedisStream {
             receipt_id: context.receipt_id,
             block_height: context.block_height,
             block_timestamp_nanosec: context.block_timestamp_nanosec,
-            token_id: context.contract_id,
-        });
+            token_id: context.contract_id.clone(),
+        };
+        self.add_event_and_context(FtBurnEvent::ID, &event, &context)
     }
 
-    async fn flush_events(&mut self, block_height: BlockHeight) {
-        self.mint_stream
-            .flush_events(block_height, self.max_stream_size)
-            .await
-            .expect("Failed to flush mint stream");
-        self.transfer_stream
-            .flush_events(block_height, self.max_stream_size)
-            .await
-            .expect("Failed to flush transfer stream");
-        self.burn_stream
-            .flush_events(block_height, self.max_stream_size)
-            .await
-            .expect("Failed to flush burn stream");
+    async fn handle_mt_mint(
+        &mut self,
+        mint: MtMintEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            MT_MINT_STREAM,
+            &context.contract_id,
+            &[&mint.owner_id],
+            mint.amounts.iter().max().copied(),
+        ) {
+            return Ok(());
+        }
+        self.add_event_with_context(MT_MINT_STREAM, &mint, &context)
+    }
+
+    async fn handle_mt_transfer(
+        &mut self,
+        transfer: MtTransferEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            MT_TRANSFER_STREAM,
+            &context.contract_id,
+            &[&transfer.old_owner_id, &transfer.new_owner_id],
+            transfer.amounts.iter().max().copied(),
+        ) {
+            return Ok(());
+        }
+        self.add_event_with_context(MT_TRANSFER_STREAM, &transfer, &context)
+    }
+
+    async fn handle_mt_burn(
+        &mut self,
+        burn: MtBurnEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            MT_BURN_STREAM,
+            &context.contract_id,
+            &[&burn.owner_id],
+            burn.amounts.iter().max().copied(),
+        ) {
+            return Ok(());
+        }
+        self.add_event_with_context(MT_BURN_STREAM, &burn, &context)
+    }
+
+    async fn handle_reverted_mint(
+        &mut self,
+        mint: near_utils::FtMintEvent,
+        context: EventContext,
+        error: TxExecutionError,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            FT_MINT_REVERTED_STREAM,
+            &context.contract_id,
+            &[&mint.owner_id],
+            Some(mint.amount),
+        ) {
+            return Ok(());
+        }
+        self.add_reverted_event(FT_MINT_REVERTED_STREAM, &mint, &context, &error)
+    }
+
+    async fn handle_reverted_transfer(
+        &mut self,
+        transfer: near_utils::FtTransferEvent,
+        context: EventContext,
+        error: TxExecutionError,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            FT_TRANSFER_REVERTED_STREAM,
+            &context.contract_id,
+            &[&transfer.old_owner_id, &transfer.new_owner_id],
+            Some(transfer.amount),
+        ) {
+            return Ok(());
+        }
+        self.add_reverted_event(FT_TRANSFER_REVERTED_STREAM, &transfer, &context, &error)
+    }
+
+    async fn handle_reverted_burn(
+        &mut self,
+        burn: near_utils::FtBurnEvent,
+        context: EventContext,
+        error: TxExecutionError,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            FT_BURN_REVERTED_STREAM,
+            &context.contract_id,
+            &[&burn.owner_id],
+            Some(burn.amount),
+        ) {
+            return Ok(());
+        }
+        self.add_reverted_event(FT_BURN_REVERTED_STREAM, &burn, &context, &error)
+    }
+
+    async fn handle_effective_transfer(
+        &mut self,
+        transfer: FtEffectiveTransferEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            FT_TRANSFER_EFFECTIVE_STREAM,
+            &context.contract_id,
+            &[&transfer.old_owner_id, &transfer.new_owner_id],
+            Some(transfer.amount),
+        ) {
+            return Ok(());
+        }
+        self.add_event_with_context(FT_TRANSFER_EFFECTIVE_STREAM, &transfer, &context)
+    }
+
+    async fn handle_near_wrap(
+        &mut self,
+        wrap: NearWrapEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            NEAR_WRAP_STREAM,
+            &context.contract_id,
+            &[&wrap.account_id],
+            Some(wrap.amount),
+        ) {
+            return Ok(());
+        }
+        self.add_event_with_context(NEAR_WRAP_STREAM, &wrap, &context)
+    }
+
+    async fn handle_storage(
+        &mut self,
+        storage: FtStorageEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            FT_STORAGE_STREAM,
+            &context.contract_id,
+            &[&storage.account_id],
+            storage.amount,
+        ) {
+            return Ok(());
+        }
+        self.add_event_with_context(FT_STORAGE_STREAM, &storage, &context)
+    }
+
+    async fn handle_supply_change(&mut self, change: SupplyChange) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(FT_SUPPLY_STREAM, &change.token_id, &[], None) {
+            return Ok(());
+        }
+        self.add_event(FT_SUPPLY_STREAM, &change, change.event_id())
+    }
+
+    async fn handle_token_discovered(
+        &mut self,
+        token: FtTokenDiscoveredEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(FT_TOKEN_DISCOVERED_STREAM, &token.token_id, &[], None) {
+            return Ok(());
+        }
+        self.add_event_with_context(FT_TOKEN_DISCOVERED_STREAM, &token, &context)
+    }
+
+    async fn handle_token_deployed(
+        &mut self,
+        token: FtTokenDeployedEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(FT_TOKEN_DEPLOYED_STREAM, &token.token_id, &[], None) {
+            return Ok(());
+        }
+        self.add_event_with_context(FT_TOKEN_DEPLOYED_STREAM, &token, &context)
+    }
+
+    async fn handle_near_balance_change(
+        &mut self,
+        change: NearBalanceChange,
+    ) -> Result<(), FtHandlerError> {
+        if !self.passes_filter(
+            NEAR_BALANCE_CHANGE_STREAM,
+            &"near".parse().unwrap(),
+            &[&change.account_id],
+            None,
+        ) {
+            return Ok(());
+        }
+        self.add_event(NEAR_BALANCE_CHANGE_STREAM, &change, change.event_id())
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), FtHandlerError> {
+        // Empty blocks are written too, to move the checkpoint
+        let entries = std::mem::take(&mut self.pending);
+
+        let result: Result<(), FtHandlerError> = async {
+            // Never write a block while older ones are still waiting on disk
+            self.drain_spill_queue().await?;
+            if let Some(queue) = self.spill_queue.as_mut() {
+                if !queue.is_empty() {
+                    queue.push(block_height, &entries)?;
+                    return Ok(());
+                }
+            }
+
+            match self.write_block_with_retries(block_height, &entries).await {
+                Ok(()) => Ok(()),
+                Err(e) => match self.spill_queue.as_mut() {
+                    Some(queue) => {
+                        log::error!(
+                            "Failed to write block {block_height} to Redis, spilling to disk: {e}"
+                        );
+                        queue.push(block_height, &entries)?;
+                        Ok(())
+                    }
+                    None => Err(e.into()),
+                },
+            }
+        }
+        .await;
+        if result.is_err() {
+            // Neither written nor spilled, keep the events so that flushing
+            // the block again writes them
+            self.pending = entries;
+        }
+        result
     }
 }
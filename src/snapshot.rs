@@ -0,0 +1,92 @@
+//! State of handlers saved to a JSON file every few blocks, so that indexing
+//! can continue from it after a restart
+
+use std::path::{Path, PathBuf};
+
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::spill_queue::write_atomically;
+use crate::FtHandlerError;
+
+/// Contents of a snapshot file
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot<T> {
+    /// Last block whose events are included in the entries
+    pub block_height: Option<BlockHeight>,
+    pub entries: Vec<T>,
+}
+
+impl<T: DeserializeOwned> Snapshot<T> {
+    pub fn load(path: &Path) -> Result<Self, FtHandlerError> {
+        let json = std::fs::read(path)?;
+        serde_json::from_slice(&json)
+            .map_err(|e| FtHandlerError::Other(format!("Invalid snapshot {}: {e}", path.display())))
+    }
+}
+
+impl<T: Serialize> Snapshot<T> {
+    pub fn save(&self, path: &Path) -> Result<(), FtHandlerError> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| FtHandlerError::Other(format!("Failed to serialize snapshot: {e}")))?;
+        write_atomically(path, &json)?;
+        Ok(())
+    }
+}
+
+/// Where a handler saves its snapshots, and after how many blocks
+pub(crate) struct SnapshotSchedule {
+    path: Option<PathBuf>,
+    interval: BlockHeight,
+    last_height: Option<BlockHeight>,
+}
+
+impl Default for SnapshotSchedule {
+    fn default() -> Self {
+        Self {
+            path: None,
+            interval: 1,
+            last_height: None,
+        }
+    }
+}
+
+impl SnapshotSchedule {
+    /// Schedule of a handler restored from a snapshot of `block_height`, so
+    /// the next snapshot is due `interval` blocks after it
+    pub fn restored(block_height: Option<BlockHeight>) -> Self {
+        Self {
+            last_height: block_height,
+            ..Default::default()
+        }
+    }
+
+    pub fn enable(&mut self, path: PathBuf, interval: BlockHeight) {
+        self.path = Some(path);
+        self.interval = interval.max(1);
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Path to save a snapshot to after `block_height`, if one is due
+    pub fn due(&self, block_height: BlockHeight) -> Option<&Path> {
+        let due = match self.last_height {
+            Some(last) => block_height >= last + self.interval,
+            None => true,
+        };
+        self.path.as_deref().filter(|_| due)
+    }
+
+    pub fn saved(&mut self, block_height: BlockHeight) {
+        self.last_height = Some(block_height);
+    }
+}
+
+/// Whether the events of `block_height` are already included in state that
+/// includes all blocks up to `processed`
+pub(crate) fn is_processed(processed: Option<BlockHeight>, block_height: BlockHeight) -> bool {
+    processed.is_some_and(|processed| block_height <= processed)
+}
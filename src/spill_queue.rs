@@ -0,0 +1,114 @@
+//! Durable on-disk FIFO of per-block payloads, used to hold events while the
+//! destination they're meant for is unavailable.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Each block is stored in its own `{block_height}.json` file. Blocks must be
+/// pushed in increasing height order, and are popped in the same order.
+pub struct SpillQueue<T> {
+    dir: PathBuf,
+    blocks: VecDeque<BlockHeight>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> SpillQueue<T> {
+    /// Opens the queue stored in `dir`, creating the directory if needed.
+    /// Blocks left over from a previous run are kept.
+    pub fn open(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let mut blocks = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(height) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<BlockHeight>().ok())
+                {
+                    blocks.push(height);
+                }
+            }
+        }
+        blocks.sort_unstable();
+        if !blocks.is_empty() {
+            log::info!(
+                "Spill queue {} has {} blocks left from a previous run",
+                dir.display(),
+                blocks.len()
+            );
+        }
+        Ok(Self {
+            dir,
+            blocks: blocks.into(),
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Height of the most recently pushed block
+    pub fn last_block_height(&self) -> Option<BlockHeight> {
+        self.blocks.back().copied()
+    }
+
+    fn path(&self, block_height: BlockHeight) -> PathBuf {
+        self.dir.join(format!("{block_height}.json"))
+    }
+
+    pub fn push(&mut self, block_height: BlockHeight, value: &T) -> std::io::Result<()> {
+        if let Some(last) = self.blocks.back() {
+            if *last >= block_height {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Block {block_height} pushed after block {last}"),
+                ));
+            }
+        }
+        let json = serde_json::to_vec(value)?;
+        write_atomically(&self.path(block_height), &json)?;
+        self.blocks.push_back(block_height);
+        Ok(())
+    }
+
+    /// Reads the oldest block without removing it
+    pub fn front(&self) -> std::io::Result<Option<(BlockHeight, T)>> {
+        let Some(block_height) = self.blocks.front().copied() else {
+            return Ok(None);
+        };
+        let json = std::fs::read(self.path(block_height))?;
+        Ok(Some((block_height, serde_json::from_slice(&json)?)))
+    }
+
+    /// Removes the oldest block, after it has been delivered
+    pub fn pop_front(&mut self) -> std::io::Result<()> {
+        if let Some(block_height) = self.blocks.front().copied() {
+            std::fs::remove_file(self.path(block_height))?;
+            self.blocks.pop_front();
+        }
+        Ok(())
+    }
+}
+
+/// Writes to a temporary file and renames it, so a crash never leaves a
+/// partially written file, such as a block in the queue
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(tmp_path, path)
+}
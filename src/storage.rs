@@ -0,0 +1,216 @@
+//! NEP-145 storage management: `storage_deposit`, `storage_withdraw` and
+//! `storage_unregister` on token contracts
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use inindexer::near_indexer_primitives::views::{ActionView, ExecutionStatusView, ReceiptEnumView};
+use inindexer::TransactionReceipt;
+use serde::{Deserialize, Serialize};
+
+use crate::serde_utils::{dec_format, option_dec_format};
+use crate::{EventContext, FtEvent, NativeTransferKind, ReceiptEvent};
+
+/// Log of `near-contract-standards` when the deposit is refunded because the
+/// account is already registered
+const ALREADY_REGISTERED_LOG: &str = "The account is already registered, refunding the deposit";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum StorageEventKind {
+    Deposit,
+    Withdraw,
+    Unregister,
+}
+
+/// Storage balance of an account, as returned by the contract
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StorageBalance {
+    #[serde(with = "dec_format")]
+    pub total: u128,
+    #[serde(with = "dec_format")]
+    pub available: u128,
+}
+
+/// A successful call to one of the NEP-145 methods
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FtStorageEvent {
+    pub kind: StorageEventKind,
+    /// Account whose storage is paid for, which is not always the caller for
+    /// deposits
+    pub account_id: AccountId,
+    /// NEAR attached to `storage_deposit`, or requested in `storage_withdraw`.
+    /// `None` if the whole available balance is withdrawn.
+    #[serde(with = "option_dec_format")]
+    pub amount: Option<u128>,
+    /// Storage balance after the call, if the contract returned it
+    pub storage_balance: Option<StorageBalance>,
+    /// The account was registered before `storage_deposit`, so the deposit
+    /// was refunded. Only detected for contracts that use
+    /// `near-contract-standards`.
+    pub already_registered: bool,
+    /// `storage_unregister` was called with `force`
+    pub force: bool,
+    /// Tokens burned by a forced `storage_unregister`
+    #[serde(with = "option_dec_format")]
+    pub burned_amount: Option<u128>,
+    /// [`EventContext::event_id`] of the NEAR attached to the call
+    pub deposit_event_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StorageDepositArgs {
+    account_id: Option<AccountId>,
+}
+
+#[derive(Deserialize)]
+struct StorageWithdrawArgs {
+    #[serde(default, with = "option_dec_format")]
+    amount: Option<u128>,
+}
+
+#[derive(Deserialize)]
+struct StorageUnregisterArgs {
+    force: Option<bool>,
+}
+
+/// Recognizes storage management calls in a successful receipt, given the
+/// events that were found in it. Returns the index of the action with each
+/// event.
+pub(crate) fn get_storage_events(
+    receipt: &TransactionReceipt,
+    events: &[(ReceiptEvent, EventContext)],
+) -> Vec<(usize, FtStorageEvent)> {
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return Vec::new();
+    };
+    let predecessor_id = &receipt.receipt.receipt.predecessor_id;
+    let outcome = &receipt.receipt.execution_outcome.outcome;
+    let return_value = match &outcome.status {
+        ExecutionStatusView::SuccessValue(value) => Some(&value[..]),
+        _ => None,
+    };
+    let deposit_event_id = |action_index: usize| {
+        events
+            .iter()
+            .find(|(_, context)| {
+                context.native_transfer == Some(NativeTransferKind::FunctionCallDeposit)
+                    && context.action_index == Some(action_index)
+            })
+            .map(|(_, context)| context.event_id())
+    };
+    // Only the last action's return value is the result of the receipt
+    let is_last_action = |action_index: usize| action_index + 1 == actions.len();
+
+    let mut storage_events = Vec::new();
+    for (action_index, action) in actions.iter().enumerate() {
+        let ActionView::FunctionCall {
+            method_name,
+            args,
+            deposit,
+            ..
+        } = action
+        else {
+            continue;
+        };
+        let storage_balance = return_value
+            .filter(|_| is_last_action(action_index))
+            .and_then(|value| serde_json::from_slice::<StorageBalance>(value).ok());
+        match method_name.as_str() {
+            "storage_deposit" => {
+                let Ok(args) = serde_json::from_slice::<StorageDepositArgs>(args) else {
+                    continue;
+                };
+                storage_events.push((
+                    action_index,
+                    FtStorageEvent {
+                        kind: StorageEventKind::Deposit,
+                        account_id: args.account_id.unwrap_or_else(|| predecessor_id.clone()),
+                        amount: Some(*deposit),
+                        storage_balance,
+                        already_registered: outcome
+                            .logs
+                            .iter()
+                            .any(|log| log == ALREADY_REGISTERED_LOG),
+                        force: false,
+                        burned_amount: None,
+                        deposit_event_id: deposit_event_id(action_index),
+                    },
+                ));
+            }
+            "storage_withdraw" => {
+                let Ok(args) = serde_json::from_slice::<StorageWithdrawArgs>(args) else {
+                    continue;
+                };
+                storage_events.push((
+                    action_index,
+                    FtStorageEvent {
+                        kind: StorageEventKind::Withdraw,
+                        account_id: predecessor_id.clone(),
+                        amount: args.amount,
+                        storage_balance,
+                        already_registered: false,
+                        force: false,
+                        burned_amount: None,
+                        deposit_event_id: deposit_event_id(action_index),
+                    },
+                ));
+            }
+            "storage_unregister" => {
+                let Ok(args) = serde_json::from_slice::<StorageUnregisterArgs>(args) else {
+                    continue;
+                };
+                // `false` is returned if the account wasn't registered
+                if is_last_action(action_index)
+                    && return_value.and_then(|value| serde_json::from_slice(value).ok())
+                        == Some(false)
+                {
+                    continue;
+                }
+                let force = args.force.unwrap_or(false);
+                storage_events.push((
+                    action_index,
+                    FtStorageEvent {
+                        kind: StorageEventKind::Unregister,
+                        account_id: predecessor_id.clone(),
+                        amount: None,
+                        storage_balance: None,
+                        already_registered: false,
+                        force,
+                        burned_amount: if force {
+                            get_burned_amount(predecessor_id, &outcome.logs, events)
+                        } else {
+                            None
+                        },
+                        deposit_event_id: deposit_event_id(action_index),
+                    },
+                ));
+            }
+            _ => {}
+        }
+    }
+    storage_events
+}
+
+/// Balance burned when an account is force-unregistered, from the NEP-141 burn
+/// event, or from the `Closed @{account_id} with {balance}` log of older
+/// versions of `near-contract-standards`
+fn get_burned_amount(
+    account_id: &AccountId,
+    logs: &[String],
+    events: &[(ReceiptEvent, EventContext)],
+) -> Option<u128> {
+    let burned = events
+        .iter()
+        .filter_map(|(event, context)| match event {
+            ReceiptEvent::Ft(FtEvent::Burn(burn))
+                if context.native_transfer.is_none() && &burn.owner_id == account_id =>
+            {
+                Some(burn.amount)
+            }
+            _ => None,
+        })
+        .reduce(u128::saturating_add);
+    burned.or_else(|| {
+        let prefix = format!("Closed @{account_id} with ");
+        logs.iter()
+            .find_map(|log| log.strip_prefix(&prefix)?.parse().ok())
+    })
+}
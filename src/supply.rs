@@ -0,0 +1,309 @@
+//! Total supply of each token, folded from mint and burn events
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_utils::{FtBurnEvent, FtMintEvent};
+use serde::{Deserialize, Serialize};
+
+use crate::account_pattern::AccountPattern;
+use crate::combinators::{impl_event_handler, BlockEvent, HandlerEvent};
+use crate::serde_utils::{dec_format, option_dec_format, signed_dec_format};
+use crate::snapshot::{is_processed, Snapshot, SnapshotSchedule};
+use crate::{EventContext, FtEventHandler, FtHandlerError};
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum SupplyAnomaly {
+    /// More tokens were burned than the known supply
+    BurnExceedsSupply {
+        #[serde(with = "dec_format")]
+        amount: u128,
+        #[serde(with = "dec_format")]
+        supply: u128,
+        event_id: String,
+    },
+    /// Tokens were minted in a receipt from an account that is not one of
+    /// the allowed minters of the token
+    UnexpectedMinter {
+        predecessor_id: AccountId,
+        #[serde(with = "dec_format")]
+        amount: u128,
+        event_id: String,
+    },
+}
+
+/// Mints and burns of a token in one block
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SupplyChange {
+    pub token_id: AccountId,
+    pub block_height: BlockHeight,
+    #[serde(with = "dec_format")]
+    pub minted: u128,
+    #[serde(with = "dec_format")]
+    pub burned: u128,
+    #[serde(with = "signed_dec_format")]
+    pub net_change: i128,
+    /// Supply after the block, if the supply before the first indexed event
+    /// of the token is known
+    #[serde(with = "option_dec_format")]
+    pub supply: Option<u128>,
+    pub anomalies: Vec<SupplyAnomaly>,
+}
+
+impl SupplyChange {
+    pub fn event_id(&self) -> String {
+        format!("{}-supply-{}", self.block_height, self.token_id)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    token_id: AccountId,
+    #[serde(with = "dec_format")]
+    supply: u128,
+}
+
+/// Handler that passes all events to `handler`, and before each
+/// [`flush_events`](FtEventHandler::flush_events) calls
+/// [`handle_supply_change`](FtEventHandler::handle_supply_change) for every
+/// token that was minted or burned in the block.
+///
+/// The supply of a token is only known if it was set with
+/// [`with_initial_supply`](Self::with_initial_supply), or if the tracker was
+/// created with [`with_complete_history`](Self::with_complete_history).
+/// Otherwise only the net change is reported. Known supplies are only kept in
+/// memory, so save them with [`snapshot`](Self::snapshot) or
+/// [`with_snapshots`](Self::with_snapshots) to continue after a restart with
+/// [`restore`](Self::restore). Mints and burns of blocks that are already
+/// included in the supplies are not counted again.
+pub struct SupplyTracker<H> {
+    pub handler: H,
+    supplies: HashMap<AccountId, u128>,
+    /// Last block whose events are included in the supplies
+    block_height: Option<BlockHeight>,
+    complete_history: bool,
+    allowed_minters: HashMap<AccountId, Vec<AccountPattern>>,
+    /// Changes of the block that is being processed, sorted by token
+    pending: BTreeMap<AccountId, SupplyChange>,
+    snapshots: SnapshotSchedule,
+}
+
+impl<H: FtEventHandler> SupplyTracker<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler,
+            supplies: HashMap::new(),
+            block_height: None,
+            complete_history: false,
+            allowed_minters: HashMap::new(),
+            pending: BTreeMap::new(),
+            snapshots: SnapshotSchedule::default(),
+        }
+    }
+
+    /// Loads supplies saved by [`snapshot`](Self::snapshot). Indexing should
+    /// continue from the block after [`block_height`](Self::block_height).
+    pub fn restore(handler: H, path: impl AsRef<Path>) -> Result<Self, FtHandlerError> {
+        let snapshot = Snapshot::<SnapshotEntry>::load(path.as_ref())?;
+        Ok(Self {
+            supplies: snapshot
+                .entries
+                .into_iter()
+                .map(|entry| (entry.token_id, entry.supply))
+                .collect(),
+            block_height: snapshot.block_height,
+            snapshots: SnapshotSchedule::restored(snapshot.block_height),
+            ..Self::new(handler)
+        })
+    }
+
+    /// Saves a snapshot to `path` after every `interval` blocks
+    pub fn with_snapshots(mut self, path: impl Into<PathBuf>, interval: BlockHeight) -> Self {
+        self.snapshots.enable(path.into(), interval);
+        self
+    }
+
+    /// Writes all known supplies and the block they are valid at to `path`
+    pub fn snapshot(&self, path: impl AsRef<Path>) -> Result<(), FtHandlerError> {
+        Snapshot {
+            block_height: self.block_height,
+            entries: self
+                .supplies
+                .iter()
+                .map(|(token_id, supply)| SnapshotEntry {
+                    token_id: token_id.clone(),
+                    supply: *supply,
+                })
+                .collect(),
+        }
+        .save(path.as_ref())
+    }
+
+    /// Last block whose events are included in the supplies
+    pub fn block_height(&self) -> Option<BlockHeight> {
+        self.block_height
+    }
+
+    /// Tokens that were not seen before start with a supply of 0. Use it when
+    /// indexing from before the tokens were created, and restore the supplies
+    /// from a snapshot when indexing continues after a restart.
+    pub fn with_complete_history(mut self) -> Self {
+        self.complete_history = true;
+        self
+    }
+
+    /// Supply of the token before the first block that will be indexed
+    pub fn with_initial_supply(mut self, token_id: AccountId, supply: u128) -> Self {
+        self.supplies.insert(token_id, supply);
+        self
+    }
+
+    /// Mints of `token_id` in receipts from other accounts are reported as
+    /// [`SupplyAnomaly::UnexpectedMinter`]
+    pub fn with_allowed_minters(
+        mut self,
+        token_id: AccountId,
+        minters: Vec<AccountPattern>,
+    ) -> Self {
+        self.allowed_minters.insert(token_id, minters);
+        self
+    }
+
+    /// Known supply of a token after the last flushed block
+    pub fn supply(&self, token_id: &AccountId) -> Option<u128> {
+        self.supplies.get(token_id).copied()
+    }
+
+    fn pending_change(&mut self, context: &EventContext) -> &mut SupplyChange {
+        self.pending
+            .entry(context.contract_id.clone())
+            .or_insert_with(|| SupplyChange {
+                token_id: context.contract_id.clone(),
+                block_height: context.block_height,
+                minted: 0,
+                burned: 0,
+                net_change: 0,
+                supply: None,
+                anomalies: Vec::new(),
+            })
+    }
+
+    /// Supply before the pending changes of the block
+    fn supply_before_block(&self, token_id: &AccountId) -> Option<u128> {
+        match self.supplies.get(token_id) {
+            Some(supply) => Some(*supply),
+            None if self.complete_history => Some(0),
+            None => None,
+        }
+    }
+
+    fn add_mint(&mut self, mint: &FtMintEvent, context: &EventContext) {
+        if is_processed(self.block_height, context.block_height) {
+            return;
+        }
+        let unexpected_minter =
+            self.allowed_minters
+                .get(&context.contract_id)
+                .is_some_and(|minters| {
+                    !minters
+                        .iter()
+                        .any(|minter| minter.matches(&context.predecessor_id))
+                });
+        let change = self.pending_change(context);
+        change.minted = change.minted.saturating_add(mint.amount);
+        change.net_change = change.net_change.saturating_add_unsigned(mint.amount);
+        if unexpected_minter {
+            log::warn!(
+                "{} minted {} of {} in {}",
+                context.predecessor_id,
+                mint.amount,
+                context.contract_id,
+                context.receipt_id
+            );
+            change.anomalies.push(SupplyAnomaly::UnexpectedMinter {
+                predecessor_id: context.predecessor_id.clone(),
+                amount: mint.amount,
+                event_id: context.event_id(),
+            });
+        }
+    }
+
+    fn add_burn(&mut self, burn: &FtBurnEvent, context: &EventContext) {
+        if is_processed(self.block_height, context.block_height) {
+            return;
+        }
+        let supply_before_block = self.supply_before_block(&context.contract_id);
+        let change = self.pending_change(context);
+        let supply = supply_before_block.and_then(|supply| {
+            u128::try_from(change.net_change.saturating_add_unsigned(supply)).ok()
+        });
+        change.burned = change.burned.saturating_add(burn.amount);
+        change.net_change = change.net_change.saturating_sub_unsigned(burn.amount);
+        if let Some(supply) = supply {
+            if burn.amount > supply {
+                log::warn!(
+                    "Burned {} of {} in {}, but the supply is {supply}",
+                    burn.amount,
+                    context.contract_id,
+                    context.receipt_id
+                );
+                change.anomalies.push(SupplyAnomaly::BurnExceedsSupply {
+                    amount: burn.amount,
+                    supply,
+                    event_id: context.event_id(),
+                });
+            }
+        }
+    }
+
+    async fn handle(
+        &mut self,
+        event: HandlerEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        match &event {
+            HandlerEvent::Mint(mint) => self.add_mint(mint, &context),
+            HandlerEvent::Burn(burn) => self.add_burn(burn, &context),
+            _ => {}
+        }
+        event.send_to(&mut self.handler, context).await
+    }
+
+    async fn handle_block_event(&mut self, event: BlockEvent) -> Result<(), FtHandlerError> {
+        match event {
+            BlockEvent::Flush(block_height) => self.flush(block_height).await,
+            event => event.send_to(&mut self.handler).await,
+        }
+    }
+
+    async fn flush(&mut self, block_height: BlockHeight) -> Result<(), FtHandlerError> {
+        let pending = std::mem::take(&mut self.pending);
+        let mut supplies = Vec::new();
+        for (token_id, mut change) in pending {
+            change.supply = self.supply_before_block(&token_id).map(|supply| {
+                u128::try_from(change.net_change.saturating_add_unsigned(supply)).unwrap_or(0)
+            });
+            if let Some(supply) = change.supply {
+                supplies.push((token_id, supply));
+            }
+            self.handler.handle_supply_change(change).await?;
+        }
+        self.handler.flush_events(block_height).await?;
+        if is_processed(self.block_height, block_height) {
+            return Ok(());
+        }
+        // Only updated once the block is delivered, so a retried block isn't
+        // counted twice
+        self.supplies.extend(supplies);
+        self.block_height = Some(block_height);
+
+        if let Some(path) = self.snapshots.due(block_height) {
+            self.snapshot(path)?;
+            self.snapshots.saved(block_height);
+        }
+        Ok(())
+    }
+}
+
+impl_event_handler!(SupplyTracker<H>, [H] where [H: FtEventHandler]);
@@ -1,14 +1,96 @@
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use inindexer::{
-    near_indexer_primitives::types::{AccountId, BlockHeight},
+    message_provider::ParallelProviderStreamer,
+    near_indexer_primitives::{
+        near_primitives::errors::TxExecutionError,
+        types::{AccountId, BlockHeight},
+        views::StateChangeWithCauseView,
+        CryptoHash,
+    },
     near_utils::{FtBurnEvent, FtMintEvent, FtTransferEvent},
     neardata::NeardataProvider,
-    run_indexer, BlockRange, IndexerOptions, PreprocessTransactionsSettings,
+    run_indexer, BlockRange, IncompleteTransaction, IndexerOptions, PreprocessTransactionsSettings,
+    TransactionReceipt,
 };
+use serde_json::json;
+
+use ft_indexer::balance_changes::{NearBalanceChange, NearBalanceChanges};
+use ft_indexer::balance_tracker::{BalanceTracker, NEAR_TOKEN_ID};
+use ft_indexer::combinators::{Filter, HandlerEvent, Map, Tee};
+use ft_indexer::deposit_category::{DepositCategory, DepositCategoryRules};
+use ft_indexer::discovery::{
+    DeploymentMatch, FtEventKind, FtTokenDeployedEvent, TokenDeploymentOptions, TokenRegistry,
+};
+use ft_indexer::filter::FilterConfig;
+use ft_indexer::fixtures::{self, FileProvider};
+use ft_indexer::legacy_log::{ContractScope, LegacyLogParser, TknTransferLogParser};
+use ft_indexer::mt::{MtEventLog, MtTransferEvent};
+use ft_indexer::reconciliation::{Reconciler, RpcClient};
+use ft_indexer::redis_handler::{PushToRedisStream, FT_TRANSFER_REVERTED_STREAM};
+use ft_indexer::spill_queue::SpillQueue;
+use ft_indexer::storage::{FtStorageEvent, StorageBalance, StorageEventKind};
+use ft_indexer::supply::{SupplyAnomaly, SupplyChange, SupplyTracker};
+use ft_indexer::transfer_call::{FtEffectiveTransferEvent, TransferCallContext};
+use ft_indexer::verification::{TokenVerificationOptions, UnverifiedTokenPolicy};
+use ft_indexer::wrap::{NearWrapEvent, WrapKind};
+use ft_indexer::{
+    EventContext, FtEvent, FtEventHandler, FtHandlerError, FtIndexer, FtIndexerOptions,
+    NativeTransferKind,
+};
+
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+
+fn test_options(start_inclusive: BlockHeight, end_exclusive: BlockHeight) -> IndexerOptions {
+    IndexerOptions {
+        preprocess_transactions: Some(PreprocessTransactionsSettings {
+            prefetch_blocks: 0,
+            postfetch_blocks: 0,
+        }),
+        ..IndexerOptions::default_with_range(BlockRange::Range {
+            start_inclusive,
+            end_exclusive: Some(end_exclusive),
+        })
+    }
+}
 
-use ft_indexer::{EventContext, FtEventHandler, FtIndexer};
+/// Runs the indexer on blocks recorded in `fixtures/`. A range that hasn't
+/// been recorded fails the test, unless `RECORD_FIXTURES=1` is set, in which
+/// case it's fetched from mainnet and saved there, so commit the new fixtures
+/// together with the test.
+async fn run_on_fixtures<T: FtEventHandler + Send + Sync + 'static>(
+    indexer: &mut FtIndexer<T>,
+    start_inclusive: BlockHeight,
+    end_exclusive: BlockHeight,
+) {
+    let dir = fixtures::range_dir(FIXTURES_DIR, start_inclusive, end_exclusive);
+    if !fixtures::is_recorded(&dir) {
+        assert!(
+            std::env::var("RECORD_FIXTURES").is_ok_and(|value| value == "1"),
+            "Fixtures for blocks {start_inclusive}..{end_exclusive} are missing in {}. Run the tests with RECORD_FIXTURES=1 to record them from mainnet, and commit them.",
+            dir.display()
+        );
+        fixtures::record_fixtures(
+            NeardataProvider::mainnet(),
+            &dir,
+            test_options(start_inclusive, end_exclusive),
+        )
+        .await
+        .unwrap();
+    }
+
+    run_indexer(
+        indexer,
+        ParallelProviderStreamer::new(FileProvider::new(dir), 1),
+        test_options(start_inclusive, end_exclusive),
+    )
+    .await
+    .unwrap();
+}
 
 #[tokio::test]
 async fn detects_mints() {
@@ -18,46 +100,50 @@ async fn detects_mints() {
 
     #[async_trait]
     impl FtEventHandler for TestHandler {
-        async fn handle_mint(&mut self, mint: FtMintEvent, context: EventContext) {
+        async fn handle_mint(
+            &mut self,
+            mint: FtMintEvent,
+            context: EventContext,
+        ) -> Result<(), FtHandlerError> {
             self.mint_events
                 .entry(context.predecessor_id.clone())
                 .or_insert_with(Vec::new)
                 .push((mint, context));
+            Ok(())
         }
 
-        async fn handle_transfer(&mut self, _transfer: FtTransferEvent, _context: EventContext) {}
+        async fn handle_transfer(
+            &mut self,
+            _transfer: FtTransferEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn handle_burn(&mut self, _burn: FtBurnEvent, _context: EventContext) {}
+        async fn handle_burn(
+            &mut self,
+            _burn: FtBurnEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
+        async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
     }
 
     let handler = TestHandler {
         mint_events: HashMap::new(),
     };
 
-    let mut indexer = FtIndexer(handler);
+    let mut indexer = FtIndexer::new(handler);
 
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: 0,
-                postfetch_blocks: 0,
-            }),
-            ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 129_190_044,
-                end_exclusive: Some(129_190_047),
-            })
-        },
-    )
-    .await
-    .unwrap();
+    run_on_fixtures(&mut indexer, 129_190_044, 129_190_047).await;
 
     assert_eq!(
         indexer
-            .0
+            .handler
             .mint_events
             .get(&"honeybot.near".parse::<AccountId>().unwrap())
             .unwrap()
@@ -74,47 +160,51 @@ async fn detects_transfers() {
 
     #[async_trait]
     impl FtEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: FtMintEvent, _context: EventContext) {}
+        async fn handle_mint(
+            &mut self,
+            _mint: FtMintEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn handle_transfer(&mut self, transfer: FtTransferEvent, context: EventContext) {
+        async fn handle_transfer(
+            &mut self,
+            transfer: FtTransferEvent,
+            context: EventContext,
+        ) -> Result<(), FtHandlerError> {
             let entry = self
                 .transfer_events
                 .entry(context.predecessor_id.clone())
                 .or_insert_with(Vec::new);
             entry.push((transfer, context));
+            Ok(())
         }
 
-        async fn handle_burn(&mut self, _burn: FtBurnEvent, _context: EventContext) {}
+        async fn handle_burn(
+            &mut self,
+            _burn: FtBurnEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
+        async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
     }
 
     let handler = TestHandler {
         transfer_events: HashMap::new(),
     };
 
-    let mut indexer = FtIndexer(handler);
+    let mut indexer = FtIndexer::new(handler);
 
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: 0,
-                postfetch_blocks: 0,
-            }),
-            ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 129_190_058,
-                end_exclusive: Some(129_190_068),
-            })
-        },
-    )
-    .await
-    .unwrap();
+    run_on_fixtures(&mut indexer, 129_190_058, 129_190_068).await;
 
     assert_eq!(
         indexer
-            .0
+            .handler
             .transfer_events
             .get(&"dca.deltatrade.near".parse::<AccountId>().unwrap())
             .unwrap()
@@ -131,53 +221,57 @@ async fn detects_tkn_transfers() {
 
     #[async_trait]
     impl FtEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: FtMintEvent, _context: EventContext) {}
+        async fn handle_mint(
+            &mut self,
+            _mint: FtMintEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn handle_transfer(&mut self, transfer: FtTransferEvent, context: EventContext) {
+        async fn handle_transfer(
+            &mut self,
+            transfer: FtTransferEvent,
+            context: EventContext,
+        ) -> Result<(), FtHandlerError> {
             let entry = self
                 .transfer_events
                 .entry(context.predecessor_id.clone())
                 .or_insert_with(Vec::new);
             entry.push((transfer, context));
+            Ok(())
         }
 
-        async fn handle_burn(&mut self, _burn: FtBurnEvent, _context: EventContext) {}
+        async fn handle_burn(
+            &mut self,
+            _burn: FtBurnEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
+        async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
     }
 
     let handler = TestHandler {
         transfer_events: HashMap::new(),
     };
 
-    let mut indexer = FtIndexer(handler);
+    let mut indexer = FtIndexer::new(handler);
 
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: 0,
-                postfetch_blocks: 0,
-            }),
-            ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 129_163_629,
-                end_exclusive: Some(129_163_632),
-            })
-        },
-    )
-    .await
-    .unwrap();
+    run_on_fixtures(&mut indexer, 129_163_629, 129_163_632).await;
 
-    assert_eq!(
-        indexer
-            .0
-            .transfer_events
-            .get(&"intelbot.near".parse::<AccountId>().unwrap())
-            .unwrap()
-            .len(),
-        1
-    );
+    let transfers = indexer
+        .handler
+        .transfer_events
+        .get(&"intelbot.near".parse::<AccountId>().unwrap())
+        .unwrap();
+    assert_eq!(transfers.len(), 1);
+    // Legacy logs are only parsed for tokens of the TKN factory
+    let (_, context) = &transfers[0];
+    assert!(context.contract_id.as_str().ends_with(".tkn.near"));
 }
 
 #[tokio::test]
@@ -188,46 +282,50 @@ async fn detects_burns() {
 
     #[async_trait]
     impl FtEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: FtMintEvent, _context: EventContext) {}
+        async fn handle_mint(
+            &mut self,
+            _mint: FtMintEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn handle_transfer(&mut self, _transfer: FtTransferEvent, _context: EventContext) {}
+        async fn handle_transfer(
+            &mut self,
+            _transfer: FtTransferEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn handle_burn(&mut self, burn: FtBurnEvent, context: EventContext) {
+        async fn handle_burn(
+            &mut self,
+            burn: FtBurnEvent,
+            context: EventContext,
+        ) -> Result<(), FtHandlerError> {
             self.burn_events
                 .entry(context.predecessor_id.clone())
                 .or_insert_with(Vec::new)
                 .push((burn, context));
+            Ok(())
         }
 
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
+        async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
     }
 
     let handler = TestHandler {
         burn_events: HashMap::new(),
     };
 
-    let mut indexer = FtIndexer(handler);
+    let mut indexer = FtIndexer::new(handler);
 
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: 0,
-                postfetch_blocks: 0,
-            }),
-            ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 129_186_699,
-                end_exclusive: Some(129_186_710),
-            })
-        },
-    )
-    .await
-    .unwrap();
+    run_on_fixtures(&mut indexer, 129_186_699, 129_186_710).await;
 
     assert_eq!(
         indexer
-            .0
+            .handler
             .burn_events
             .get(&"shit.0xshitzu.near".parse::<AccountId>().unwrap())
             .unwrap()
@@ -244,47 +342,51 @@ async fn detects_native_near_transfers() {
 
     #[async_trait]
     impl FtEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: FtMintEvent, _context: EventContext) {}
+        async fn handle_mint(
+            &mut self,
+            _mint: FtMintEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn handle_transfer(&mut self, transfer: FtTransferEvent, context: EventContext) {
+        async fn handle_transfer(
+            &mut self,
+            transfer: FtTransferEvent,
+            context: EventContext,
+        ) -> Result<(), FtHandlerError> {
             let entry = self
                 .transfer_events
                 .entry(context.predecessor_id.clone())
                 .or_insert_with(Vec::new);
             entry.push((transfer, context));
+            Ok(())
         }
 
-        async fn handle_burn(&mut self, _burn: FtBurnEvent, _context: EventContext) {}
+        async fn handle_burn(
+            &mut self,
+            _burn: FtBurnEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
+        async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
     }
 
     let handler = TestHandler {
         transfer_events: HashMap::new(),
     };
 
-    let mut indexer = FtIndexer(handler);
+    let mut indexer = FtIndexer::new(handler);
 
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: 0,
-                postfetch_blocks: 0,
-            }),
-            ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 131_214_339,
-                end_exclusive: Some(131_214_342),
-            })
-        },
-    )
-    .await
-    .unwrap();
+    run_on_fixtures(&mut indexer, 131_214_339, 131_214_342).await;
 
     assert_eq!(
         indexer
-            .0
+            .handler
             .transfer_events
             .get(&"slimedragon.near".parse::<AccountId>().unwrap())
             .unwrap()
@@ -301,47 +403,51 @@ async fn detects_native_near_function_transfers() {
 
     #[async_trait]
     impl FtEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: FtMintEvent, _context: EventContext) {}
+        async fn handle_mint(
+            &mut self,
+            _mint: FtMintEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn handle_transfer(&mut self, transfer: FtTransferEvent, context: EventContext) {
+        async fn handle_transfer(
+            &mut self,
+            transfer: FtTransferEvent,
+            context: EventContext,
+        ) -> Result<(), FtHandlerError> {
             let entry = self
                 .transfer_events
                 .entry(context.predecessor_id.clone())
                 .or_insert_with(Vec::new);
             entry.push((transfer, context));
+            Ok(())
         }
 
-        async fn handle_burn(&mut self, _burn: FtBurnEvent, _context: EventContext) {}
+        async fn handle_burn(
+            &mut self,
+            _burn: FtBurnEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
+        async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
     }
 
     let handler = TestHandler {
         transfer_events: HashMap::new(),
     };
 
-    let mut indexer = FtIndexer(handler);
+    let mut indexer = FtIndexer::new(handler);
 
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: 0,
-                postfetch_blocks: 0,
-            }),
-            ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 131_103_427,
-                end_exclusive: Some(131_103_430),
-            })
-        },
-    )
-    .await
-    .unwrap();
+    run_on_fixtures(&mut indexer, 131_103_427, 131_103_430).await;
 
     assert_eq!(
         indexer
-            .0
+            .handler
             .transfer_events
             .get(&"fiery_drone.user.intear.near".parse::<AccountId>().unwrap())
             .unwrap()
@@ -358,47 +464,2408 @@ async fn does_not_detect_fee_refunds() {
 
     #[async_trait]
     impl FtEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: FtMintEvent, _context: EventContext) {}
+        async fn handle_mint(
+            &mut self,
+            _mint: FtMintEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn handle_transfer(&mut self, transfer: FtTransferEvent, context: EventContext) {
+        async fn handle_transfer(
+            &mut self,
+            transfer: FtTransferEvent,
+            context: EventContext,
+        ) -> Result<(), FtHandlerError> {
             let entry = self
                 .transfer_events
                 .entry(context.predecessor_id.clone())
                 .or_insert_with(Vec::new);
             entry.push((transfer, context));
+            Ok(())
         }
 
-        async fn handle_burn(&mut self, _burn: FtBurnEvent, _context: EventContext) {}
+        async fn handle_burn(
+            &mut self,
+            _burn: FtBurnEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
 
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
+        async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
     }
 
     let handler = TestHandler {
         transfer_events: HashMap::new(),
     };
 
-    let mut indexer = FtIndexer(handler);
+    let mut indexer = FtIndexer::new(handler);
 
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: 0,
-                postfetch_blocks: 0,
-            }),
-            ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 131_103_427,
-                end_exclusive: Some(131_103_430),
-            })
-        },
-    )
-    .await
-    .unwrap();
+    run_on_fixtures(&mut indexer, 131_103_427, 131_103_430).await;
 
     assert!(indexer
-        .0
+        .handler
         .transfer_events
         .get(&"system".parse::<AccountId>().unwrap())
         .is_none());
 }
+
+const TEST_PUBLIC_KEY: &str = "ed25519:11111111111111111111111111111111";
+const TEST_SIGNATURE: &str =
+    "ed25519:1111111111111111111111111111111111111111111111111111111111111111";
+
+/// Id of a receipt or transaction built by a test
+fn test_hash(name: &str) -> CryptoHash {
+    CryptoHash::hash_bytes(name.as_bytes())
+}
+
+fn transfer_action(deposit: u128) -> serde_json::Value {
+    json!({"Transfer": {"deposit": deposit.to_string()}})
+}
+
+/// A receipt executed in block 100
+struct TestReceipt {
+    /// Name that the receipt id is derived from, see [`test_hash`]
+    id: &'static str,
+    predecessor_id: &'static str,
+    receiver_id: &'static str,
+    signer_id: &'static str,
+    actions: Vec<serde_json::Value>,
+    logs: Vec<String>,
+    /// Names of the receipts that this receipt created
+    receipt_ids: Vec<&'static str>,
+    status: serde_json::Value,
+}
+
+impl Default for TestReceipt {
+    fn default() -> Self {
+        Self {
+            id: "receipt",
+            predecessor_id: "alice.near",
+            receiver_id: "token.near",
+            signer_id: "alice.near",
+            actions: Vec::new(),
+            logs: Vec::new(),
+            receipt_ids: Vec::new(),
+            status: json!({"SuccessValue": ""}),
+        }
+    }
+}
+
+impl TestReceipt {
+    fn build(self) -> TransactionReceipt {
+        let receipt_id = test_hash(self.id);
+        let receipt_ids: Vec<_> = self.receipt_ids.iter().map(|id| test_hash(id)).collect();
+        TransactionReceipt {
+            receipt: serde_json::from_value(json!({
+                "execution_outcome": {
+                    "proof": [],
+                    "block_hash": test_hash("block"),
+                    "id": receipt_id,
+                    "outcome": {
+                        "logs": self.logs,
+                        "receipt_ids": receipt_ids,
+                        "gas_burnt": 0,
+                        "tokens_burnt": "0",
+                        "executor_id": self.receiver_id,
+                        "status": self.status,
+                        "metadata": {"version": 1, "gas_profile": null},
+                    },
+                },
+                "receipt": {
+                    "predecessor_id": self.predecessor_id,
+                    "receiver_id": self.receiver_id,
+                    "receipt_id": receipt_id,
+                    "receipt": {"Action": {
+                        "signer_id": self.signer_id,
+                        "signer_public_key": TEST_PUBLIC_KEY,
+                        "gas_price": "0",
+                        "output_data_receivers": [],
+                        "input_data_ids": [],
+                        "actions": self.actions,
+                    }},
+                },
+            }))
+            .unwrap(),
+            block_height: 100,
+            block_timestamp_nanosec: 0,
+        }
+    }
+}
+
+/// Transaction from `signer_id` to `receiver_id`, where `receipts` have been
+/// executed so far
+fn test_transaction(
+    signer_id: &str,
+    receiver_id: &str,
+    actions: Vec<serde_json::Value>,
+    receipts: &[&TransactionReceipt],
+) -> IncompleteTransaction {
+    let hash = test_hash("transaction");
+    IncompleteTransaction {
+        transaction: serde_json::from_value(json!({
+            "transaction": {
+                "signer_id": signer_id,
+                "public_key": TEST_PUBLIC_KEY,
+                "nonce": 1,
+                "receiver_id": receiver_id,
+                "actions": actions,
+                "signature": TEST_SIGNATURE,
+                "hash": hash,
+            },
+            "outcome": {
+                "execution_outcome": {
+                    "proof": [],
+                    "block_hash": test_hash("block"),
+                    "id": hash,
+                    "outcome": {
+                        "logs": [],
+                        "receipt_ids": [],
+                        "gas_burnt": 0,
+                        "tokens_burnt": "0",
+                        "executor_id": signer_id,
+                        "status": {"SuccessReceiptId": test_hash("receipt")},
+                        "metadata": {"version": 1, "gas_profile": null},
+                    },
+                },
+                "receipt": null,
+            },
+        }))
+        .unwrap(),
+        receipts: receipts
+            .iter()
+            .map(|receipt| (receipt.receipt.receipt.receipt_id, Some((*receipt).clone())))
+            .collect(),
+    }
+}
+
+struct NoopHandler;
+
+#[async_trait]
+impl FtEventHandler for NoopHandler {
+    async fn handle_mint(
+        &mut self,
+        _mint: FtMintEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        _transfer: FtTransferEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        _burn: FtBurnEvent,
+        _context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+}
+
+/// Event passed to a [`RecordingHandler`]
+#[derive(Debug)]
+enum RecordedEvent {
+    Mint(FtMintEvent),
+    Transfer(FtTransferEvent),
+    Burn(FtBurnEvent),
+    MtTransfer(MtTransferEvent),
+    RevertedTransfer(FtTransferEvent, TxExecutionError),
+    EffectiveTransfer(FtEffectiveTransferEvent),
+    NearWrap(NearWrapEvent),
+    Storage(FtStorageEvent),
+    TokenDeployed(FtTokenDeployedEvent),
+}
+
+type RecordedEvents = Arc<Mutex<Vec<(RecordedEvent, EventContext)>>>;
+
+/// Records the events that tests check, with their context
+struct RecordingHandler {
+    events: RecordedEvents,
+}
+
+impl RecordingHandler {
+    fn record(
+        &mut self,
+        event: RecordedEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.events.lock().unwrap().push((event, context));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FtEventHandler for RecordingHandler {
+    async fn handle_mint(
+        &mut self,
+        mint: FtMintEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.record(RecordedEvent::Mint(mint), context)
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: FtTransferEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.record(RecordedEvent::Transfer(transfer), context)
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: FtBurnEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.record(RecordedEvent::Burn(burn), context)
+    }
+
+    async fn handle_mt_transfer(
+        &mut self,
+        transfer: MtTransferEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.record(RecordedEvent::MtTransfer(transfer), context)
+    }
+
+    async fn handle_reverted_transfer(
+        &mut self,
+        transfer: FtTransferEvent,
+        context: EventContext,
+        error: TxExecutionError,
+    ) -> Result<(), FtHandlerError> {
+        self.record(RecordedEvent::RevertedTransfer(transfer, error), context)
+    }
+
+    async fn handle_effective_transfer(
+        &mut self,
+        transfer: FtEffectiveTransferEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.record(RecordedEvent::EffectiveTransfer(transfer), context)
+    }
+
+    async fn handle_near_wrap(
+        &mut self,
+        wrap: NearWrapEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.record(RecordedEvent::NearWrap(wrap), context)
+    }
+
+    async fn handle_storage(
+        &mut self,
+        storage: FtStorageEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.record(RecordedEvent::Storage(storage), context)
+    }
+
+    async fn handle_token_deployed(
+        &mut self,
+        token: FtTokenDeployedEvent,
+        context: EventContext,
+    ) -> Result<(), FtHandlerError> {
+        self.record(RecordedEvent::TokenDeployed(token), context)
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+        Ok(())
+    }
+}
+
+fn recording_indexer(options: FtIndexerOptions) -> (FtIndexer<RecordingHandler>, RecordedEvents) {
+    let recorded = RecordedEvents::default();
+    let handler = RecordingHandler {
+        events: recorded.clone(),
+    };
+    (FtIndexer::with_options(handler, options), recorded)
+}
+
+fn take_events(recorded: &RecordedEvents) -> Vec<(RecordedEvent, EventContext)> {
+    std::mem::take(&mut *recorded.lock().unwrap())
+}
+
+#[tokio::test]
+async fn detects_events_of_synthetic_receipts() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    let receipt = TestReceipt {
+        logs: vec![
+            r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_mint","data":[{"owner_id":"alice.near","amount":"30"}]}"#.to_owned(),
+            r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[{"old_owner_id":"alice.near","new_owner_id":"bob.near","amount":"20"}]}"#.to_owned(),
+            r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_burn","data":[{"owner_id":"bob.near","amount":"10"}]}"#.to_owned(),
+        ],
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("alice.near", "token.near", Vec::new(), &[]);
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Mint(mint), mint_context), (RecordedEvent::Transfer(transfer), _), (RecordedEvent::Burn(burn), burn_context)] =
+        events.as_slice()
+    else {
+        panic!("Expected a mint, a transfer and a burn, got {events:?}");
+    };
+    assert_eq!(mint.owner_id, "alice.near");
+    assert_eq!(mint.amount, 30);
+    assert_eq!(transfer.old_owner_id, "alice.near");
+    assert_eq!(transfer.new_owner_id, "bob.near");
+    assert_eq!(transfer.amount, 20);
+    assert_eq!(burn.owner_id, "bob.near");
+    assert_eq!(burn.amount, 10);
+    assert_eq!(mint_context.contract_id, "token.near");
+    assert_eq!(mint_context.receipt_id, test_hash("receipt"));
+    assert_eq!(mint_context.block_height, 100);
+    assert_eq!(burn_context.log_index, Some(2));
+}
+
+/// Empty directory for a test, removed if it's left over from a previous run
+fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("ft_indexer_{name}_{}", std::process::id()));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn spills_blocks_in_order() {
+    let dir = test_dir("spill_queue");
+    let mut queue = SpillQueue::<Vec<String>>::open(&dir).unwrap();
+    assert!(queue.is_empty());
+    assert_eq!(queue.front().unwrap(), None);
+
+    queue.push(10, &vec!["a".to_owned()]).unwrap();
+    queue
+        .push(12, &vec!["b".to_owned(), "c".to_owned()])
+        .unwrap();
+    assert!(queue.push(12, &Vec::new()).is_err());
+    assert!(queue.push(11, &Vec::new()).is_err());
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.last_block_height(), Some(12));
+
+    // Blocks are kept after a restart
+    let mut queue = SpillQueue::<Vec<String>>::open(&dir).unwrap();
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.last_block_height(), Some(12));
+    assert_eq!(queue.front().unwrap(), Some((10, vec!["a".to_owned()])));
+    // Reading doesn't remove the block
+    assert_eq!(queue.front().unwrap(), Some((10, vec!["a".to_owned()])));
+    queue.pop_front().unwrap();
+    assert!(queue.push(12, &Vec::new()).is_err());
+    queue.push(13, &Vec::new()).unwrap();
+
+    let mut queue = SpillQueue::<Vec<String>>::open(&dir).unwrap();
+    assert_eq!(
+        queue.front().unwrap(),
+        Some((12, vec!["b".to_owned(), "c".to_owned()]))
+    );
+    queue.pop_front().unwrap();
+    assert_eq!(queue.front().unwrap(), Some((13, Vec::new())));
+    queue.pop_front().unwrap();
+    assert!(queue.is_empty());
+    queue.pop_front().unwrap();
+
+    let queue = SpillQueue::<Vec<String>>::open(&dir).unwrap();
+    assert!(queue.is_empty());
+    assert_eq!(queue.last_block_height(), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn test_context(block_height: BlockHeight, contract_id: &str) -> EventContext {
+    EventContext {
+        transaction_id: CryptoHash::default(),
+        receipt_id: CryptoHash::default(),
+        block_height,
+        block_timestamp_nanosec: 0,
+        predecessor_id: "alice.near".parse().unwrap(),
+        contract_id: contract_id.parse().unwrap(),
+        log_index: Some(0),
+        event_index: Some(0),
+        action_index: None,
+        relayer_id: None,
+        native_transfer: None,
+        refund_of: None,
+        method_name: None,
+        transfer_call: None,
+        token_verified: None,
+        deposit_category: None,
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a disposable Redis at $REDIS_URL"]
+async fn writes_each_block_to_redis_once() {
+    let client = redis::Client::open(
+        std::env::var("REDIS_URL").expect("No $REDIS_URL environment variable set"),
+    )
+    .unwrap();
+    let mut connection = redis::aio::ConnectionManager::new(client).await.unwrap();
+    let checkpoint_key = "ft_indexer_test_last_block";
+    let stream = "ft_mint";
+    let _: () = redis::cmd("DEL")
+        .arg(checkpoint_key)
+        .arg(stream)
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    let stream_ids = |mut connection: redis::aio::ConnectionManager| async move {
+        let entries: Vec<(String, Vec<String>)> = redis::cmd("XRANGE")
+            .arg(stream)
+            .arg("-")
+            .arg("+")
+            .query_async(&mut connection)
+            .await
+            .unwrap();
+        entries.into_iter().map(|(id, _)| id).collect::<Vec<_>>()
+    };
+    let mint = FtMintEvent {
+        owner_id: "alice.near".parse().unwrap(),
+        amount: 100,
+        memo: None,
+    };
+
+    let mut handler = PushToRedisStream::new(connection.clone(), 100)
+        .await
+        .with_checkpoint_key(checkpoint_key);
+    assert_eq!(handler.last_processed_block().await.unwrap(), None);
+    for _ in 0..2 {
+        // The second time, as if the block was processed again after a crash
+        handler
+            .handle_mint(mint.clone(), test_context(10, "token.near"))
+            .await
+            .unwrap();
+        handler.flush_events(10).await.unwrap();
+        assert_eq!(stream_ids(connection.clone()).await, vec!["10-0"]);
+        assert_eq!(handler.last_processed_block().await.unwrap(), Some(10));
+    }
+
+    // Blocks waiting in the spill queue count as processed
+    let spill_dir = test_dir("redis_spill_queue");
+    let mut queue = SpillQueue::open(&spill_dir).unwrap();
+    queue.push(12, &Vec::new()).unwrap();
+    let mut handler = handler.with_spill_queue(queue);
+    assert_eq!(handler.last_processed_block().await.unwrap(), Some(12));
+
+    // The spilled block is written before the next one
+    handler
+        .handle_mint(mint.clone(), test_context(13, "token.near"))
+        .await
+        .unwrap();
+    handler.flush_events(13).await.unwrap();
+    let checkpoint: Option<BlockHeight> = redis::cmd("GET")
+        .arg(checkpoint_key)
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    assert_eq!(checkpoint, Some(13));
+    assert_eq!(handler.last_processed_block().await.unwrap(), Some(13));
+
+    // Blocks below the checkpoint are not written
+    handler
+        .handle_mint(mint.clone(), test_context(11, "token.near"))
+        .await
+        .unwrap();
+    handler.flush_events(11).await.unwrap();
+    assert_eq!(stream_ids(connection.clone()).await, vec!["10-0", "13-0"]);
+
+    // Without a checkpoint, blocks below the last entry of a stream are not
+    // written either, and the checkpoint is restored from the stream
+    let _: () = redis::cmd("DEL")
+        .arg(checkpoint_key)
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    handler
+        .handle_mint(mint, test_context(12, "token.near"))
+        .await
+        .unwrap();
+    handler.flush_events(12).await.unwrap();
+    assert_eq!(stream_ids(connection.clone()).await, vec!["10-0", "13-0"]);
+    assert_eq!(handler.last_processed_block().await.unwrap(), Some(13));
+    std::fs::remove_dir_all(&spill_dir).unwrap();
+}
+
+#[test]
+fn derives_event_ids() {
+    let receipt_id = CryptoHash::hash_bytes(b"receipt");
+    let log_context = EventContext {
+        receipt_id,
+        log_index: Some(2),
+        event_index: Some(1),
+        ..test_context(10, "token.near")
+    };
+    assert_eq!(log_context.event_id(), format!("{receipt_id}-log2-1"));
+    // Indexing the same receipt again gives the same id
+    assert_eq!(log_context.event_id(), log_context.clone().event_id());
+
+    let action_context = EventContext {
+        receipt_id,
+        log_index: None,
+        event_index: None,
+        action_index: Some(3),
+        ..test_context(10, "near")
+    };
+    assert_eq!(action_context.event_id(), format!("{receipt_id}-action3"));
+
+    let receipt_context = EventContext {
+        receipt_id,
+        log_index: None,
+        event_index: None,
+        ..test_context(10, "token.near")
+    };
+    assert_eq!(receipt_context.event_id(), receipt_id.to_string());
+}
+
+#[tokio::test]
+async fn combines_handlers() {
+    type Transfers = Arc<Mutex<Vec<(FtTransferEvent, EventContext)>>>;
+
+    #[derive(Default)]
+    struct TestHandler {
+        transfers: Transfers,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl FtEventHandler for TestHandler {
+        async fn handle_mint(
+            &mut self,
+            _mint: FtMintEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
+
+        async fn handle_transfer(
+            &mut self,
+            transfer: FtTransferEvent,
+            context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            if self.fail {
+                return Err(FtHandlerError::Other("Failed on purpose".to_owned()));
+            }
+            self.transfers.lock().unwrap().push((transfer, context));
+            Ok(())
+        }
+
+        async fn handle_burn(
+            &mut self,
+            _burn: FtBurnEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
+
+        async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
+    }
+
+    let transfer = |amount| FtTransferEvent {
+        old_owner_id: "alice.near".parse().unwrap(),
+        new_owner_id: "bob.near".parse().unwrap(),
+        amount,
+        memo: None,
+    };
+    let context = test_context(1, "token.near");
+
+    let all_transfers = Transfers::default();
+    let large_transfers = Transfers::default();
+    let failing = TestHandler {
+        fail: true,
+        ..Default::default()
+    };
+    let large_only = Filter::new(
+        TestHandler {
+            transfers: large_transfers.clone(),
+            fail: false,
+        },
+        |event: &HandlerEvent, _context: &EventContext| matches!(event, HandlerEvent::Transfer(transfer) if transfer.amount >= 10),
+    );
+    let enriched = Map::new(large_only, |event, context| {
+        Some((
+            event,
+            EventContext {
+                method_name: Some("enriched".to_owned()),
+                ..context
+            },
+        ))
+    });
+    let mut tee = Tee::new()
+        .with_isolated_handler(failing)
+        .with_handler(TestHandler {
+            transfers: all_transfers.clone(),
+            fail: false,
+        })
+        .with_handler(enriched);
+
+    tee.handle_transfer(transfer(5), context.clone())
+        .await
+        .unwrap();
+    tee.handle_transfer(transfer(50), context.clone())
+        .await
+        .unwrap();
+    assert_eq!(tee.errors(), vec![2, 0, 0]);
+    assert_eq!(all_transfers.lock().unwrap().len(), 2);
+    let large_transfers = large_transfers.lock().unwrap();
+    let [(large_transfer, large_context)] = large_transfers.as_slice() else {
+        panic!("Expected a single transfer, got {large_transfers:?}");
+    };
+    assert_eq!(large_transfer.amount, 50);
+    assert_eq!(large_context.method_name.as_deref(), Some("enriched"));
+
+    // Errors of handlers that are not isolated are returned, but the other
+    // handlers still receive the event
+    let all_transfers = Transfers::default();
+    let mut tee = Tee::new()
+        .with_handler(TestHandler {
+            fail: true,
+            ..Default::default()
+        })
+        .with_handler(TestHandler {
+            transfers: all_transfers.clone(),
+            fail: false,
+        });
+    assert!(tee.handle_transfer(transfer(5), context).await.is_err());
+    assert_eq!(all_transfers.lock().unwrap().len(), 1);
+}
+
+fn near_balance_change(
+    account_id: &str,
+    balance_after: u128,
+    block_height: BlockHeight,
+) -> NearBalanceChange {
+    NearBalanceChange {
+        account_id: account_id.parse().unwrap(),
+        balance_before: None,
+        balance_after,
+        locked_before: None,
+        locked_after: 0,
+        deleted: false,
+        cause: serde_json::from_value(
+            json!({"type": "receipt_processing", "receipt_hash": test_hash("receipt")}),
+        )
+        .unwrap(),
+        block_height,
+        block_timestamp_nanosec: 0,
+        index_in_block: 0,
+    }
+}
+
+#[tokio::test]
+async fn tracks_balances() {
+    let token: AccountId = "token.near".parse().unwrap();
+    let alice: AccountId = "alice.near".parse().unwrap();
+    let bob: AccountId = "bob.near".parse().unwrap();
+    let transfer = FtTransferEvent {
+        old_owner_id: alice.clone(),
+        new_owner_id: bob.clone(),
+        amount: 30,
+        memo: None,
+    };
+
+    let mut tracker = BalanceTracker::new();
+    let reader = tracker.reader();
+    tracker
+        .handle_mint(
+            FtMintEvent {
+                owner_id: alice.clone(),
+                amount: 100,
+                memo: None,
+            },
+            test_context(10, "token.near"),
+        )
+        .await
+        .unwrap();
+    tracker
+        .handle_transfer(transfer.clone(), test_context(10, "token.near"))
+        .await
+        .unwrap();
+    assert_eq!(reader.balance(&token, &alice), 0);
+    tracker.flush_events(10).await.unwrap();
+    assert_eq!(reader.block_height(), Some(10));
+    assert_eq!(reader.balance(&token, &alice), 70);
+    assert_eq!(reader.balance(&token, &bob), 30);
+    assert_eq!(
+        reader.balances_of(&bob),
+        HashMap::from([(token.clone(), 30)])
+    );
+    let mut changes = reader.last_block_changes();
+    changes.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+    assert_eq!(
+        changes
+            .iter()
+            .map(|change| (change.account_id.as_str(), change.delta, change.balance))
+            .collect::<Vec<_>>(),
+        vec![("alice.near", 70, 70), ("bob.near", 30, 30)]
+    );
+
+    let snapshot_path =
+        std::env::temp_dir().join(format!("ft_indexer_balances_{}.json", std::process::id()));
+    tracker.snapshot(&snapshot_path).unwrap();
+    let mut tracker = BalanceTracker::restore(&snapshot_path).unwrap();
+    std::fs::remove_file(&snapshot_path).unwrap();
+    let reader = tracker.reader();
+    assert_eq!(reader.block_height(), Some(10));
+    assert_eq!(reader.holders(&token).len(), 2);
+
+    // Blocks included in the snapshot are not counted twice
+    tracker
+        .handle_transfer(transfer.clone(), test_context(10, "token.near"))
+        .await
+        .unwrap();
+    tracker.flush_events(10).await.unwrap();
+    assert_eq!(reader.balance(&token, &bob), 30);
+
+    tracker
+        .handle_burn(
+            FtBurnEvent {
+                owner_id: bob.clone(),
+                amount: 30,
+                memo: None,
+            },
+            test_context(11, "token.near"),
+        )
+        .await
+        .unwrap();
+    // Events of contracts that are not known tokens are not counted
+    tracker
+        .handle_transfer(
+            transfer.clone(),
+            EventContext {
+                token_verified: Some(false),
+                ..test_context(11, "token.near")
+            },
+        )
+        .await
+        .unwrap();
+    tracker.flush_events(11).await.unwrap();
+    assert_eq!(reader.balance(&token, &bob), 0);
+    assert_eq!(reader.holders(&token).len(), 1);
+
+    // Balances that don't fit are capped instead of overflowing
+    for _ in 0..2 {
+        tracker
+            .handle_mint(
+                FtMintEvent {
+                    owner_id: alice.clone(),
+                    amount: u128::MAX,
+                    memo: None,
+                },
+                test_context(12, "token.near"),
+            )
+            .await
+            .unwrap();
+    }
+    tracker.flush_events(12).await.unwrap();
+    assert_eq!(reader.balance(&token, &alice), i128::MAX);
+
+    // NEAR balances are only kept if enabled
+    let near: AccountId = NEAR_TOKEN_ID.parse().unwrap();
+    tracker
+        .handle_near_balance_change(near_balance_change("bob.near", 500, 13))
+        .await
+        .unwrap();
+    tracker.flush_events(13).await.unwrap();
+    assert_eq!(reader.balance(&near, &bob), 0);
+    let mut tracker = tracker.with_near_balances();
+    tracker
+        .handle_near_balance_change(near_balance_change("bob.near", 500, 14))
+        .await
+        .unwrap();
+    tracker.flush_events(14).await.unwrap();
+    assert_eq!(reader.balance(&near, &bob), 500);
+}
+
+#[tokio::test]
+async fn tracks_supply() {
+    #[derive(Default)]
+    struct TestHandler {
+        supply_changes: Vec<SupplyChange>,
+    }
+
+    #[async_trait]
+    impl FtEventHandler for TestHandler {
+        async fn handle_mint(
+            &mut self,
+            _mint: FtMintEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
+
+        async fn handle_transfer(
+            &mut self,
+            _transfer: FtTransferEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
+
+        async fn handle_burn(
+            &mut self,
+            _burn: FtBurnEvent,
+            _context: EventContext,
+        ) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
+
+        async fn handle_supply_change(
+            &mut self,
+            change: SupplyChange,
+        ) -> Result<(), FtHandlerError> {
+            self.supply_changes.push(change);
+            Ok(())
+        }
+
+        async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), FtHandlerError> {
+            Ok(())
+        }
+    }
+
+    let token: AccountId = "token.near".parse().unwrap();
+    let mint = |amount| FtMintEvent {
+        owner_id: "alice.near".parse().unwrap(),
+        amount,
+        memo: None,
+    };
+    let burn = |amount| FtBurnEvent {
+        owner_id: "alice.near".parse().unwrap(),
+        amount,
+        memo: None,
+    };
+    let mut tracker = SupplyTracker::new(TestHandler::default())
+        .with_initial_supply(token.clone(), 1000)
+        .with_allowed_minters(token.clone(), vec!["token.near".parse().unwrap()]);
+
+    // The test context's predecessor is alice.near
+    tracker
+        .handle_mint(mint(500), test_context(10, "token.near"))
+        .await
+        .unwrap();
+    tracker
+        .handle_burn(burn(200), test_context(10, "token.near"))
+        .await
+        .unwrap();
+    tracker
+        .handle_mint(mint(7), test_context(10, "other.near"))
+        .await
+        .unwrap();
+    tracker.flush_events(10).await.unwrap();
+    assert_eq!(tracker.supply(&token), Some(1300));
+    let changes = std::mem::take(&mut tracker.handler.supply_changes);
+    let [other_change, token_change] = changes.as_slice() else {
+        panic!("Expected changes of 2 tokens, got {changes:?}");
+    };
+    assert_eq!(other_change.token_id, "other.near");
+    assert_eq!(other_change.net_change, 7);
+    assert_eq!(other_change.supply, None);
+    assert!(other_change.anomalies.is_empty());
+    assert_eq!(token_change.minted, 500);
+    assert_eq!(token_change.burned, 200);
+    assert_eq!(token_change.net_change, 300);
+    assert_eq!(token_change.supply, Some(1300));
+    assert!(matches!(
+        token_change.anomalies.as_slice(),
+        [SupplyAnomaly::UnexpectedMinter { amount: 500, .. }]
+    ));
+
+    tracker
+        .handle_burn(burn(2000), test_context(11, "token.near"))
+        .await
+        .unwrap();
+    tracker.flush_events(11).await.unwrap();
+    let [change] = tracker.handler.supply_changes.as_slice() else {
+        panic!("Expected a single change");
+    };
+    assert!(matches!(
+        change.anomalies.as_slice(),
+        [SupplyAnomaly::BurnExceedsSupply {
+            amount: 2000,
+            supply: 1300,
+            ..
+        }]
+    ));
+    // Nothing is minted or burned in empty blocks
+    tracker.handler.supply_changes.clear();
+    tracker.flush_events(12).await.unwrap();
+    assert!(tracker.handler.supply_changes.is_empty());
+    // More was burned than the known supply
+    assert_eq!(tracker.supply(&token), Some(0));
+
+    let snapshot_path =
+        std::env::temp_dir().join(format!("ft_indexer_supplies_{}.json", std::process::id()));
+    tracker.snapshot(&snapshot_path).unwrap();
+    let mut tracker = SupplyTracker::restore(TestHandler::default(), &snapshot_path)
+        .unwrap()
+        .with_complete_history();
+    std::fs::remove_file(&snapshot_path).unwrap();
+    assert_eq!(tracker.block_height(), Some(12));
+    assert_eq!(tracker.supply(&token), Some(0));
+
+    // Blocks included in the snapshot are not counted twice
+    tracker
+        .handle_mint(mint(100), test_context(11, "token.near"))
+        .await
+        .unwrap();
+    tracker.flush_events(11).await.unwrap();
+    assert!(tracker.handler.supply_changes.is_empty());
+    assert_eq!(tracker.supply(&token), Some(0));
+
+    tracker
+        .handle_mint(mint(50), test_context(13, "token.near"))
+        .await
+        .unwrap();
+    tracker
+        .handle_mint(mint(5), test_context(13, "new.near"))
+        .await
+        .unwrap();
+    tracker.flush_events(13).await.unwrap();
+    assert_eq!(tracker.supply(&token), Some(50));
+    // Tokens that are not in the snapshot were created after the start
+    assert_eq!(tracker.supply(&"new.near".parse().unwrap()), Some(5));
+}
+
+/// Serves `ft_balance_of` with balances from `balances`, ignoring the token,
+/// and records the requested block heights and accounts. Other accounts get
+/// an error.
+async fn mock_rpc_server(
+    balances: HashMap<String, u128>,
+    requests: Arc<Mutex<Vec<(u64, String)>>>,
+) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let body = loop {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                let Some((headers, body)) = text.split_once("\r\n\r\n") else {
+                    continue;
+                };
+                let content_length: usize = headers
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse().unwrap())
+                    })
+                    .unwrap_or(0);
+                if body.len() >= content_length {
+                    break body.to_owned();
+                }
+            };
+            let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+            let params = &request["params"];
+            assert_eq!(params["method_name"], "ft_balance_of");
+            let args = BASE64
+                .decode(params["args_base64"].as_str().unwrap())
+                .unwrap();
+            let args: serde_json::Value = serde_json::from_slice(&args).unwrap();
+            let account_id = args["account_id"].as_str().unwrap().to_owned();
+            requests
+                .lock()
+                .unwrap()
+                .push((params["block_id"].as_u64().unwrap(), account_id.clone()));
+            let response = match balances.get(&account_id) {
+                Some(balance) => json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": {
+                        "result": serde_json::to_vec(&balance.to_string()).unwrap(),
+                        "logs": [],
+                    },
+                }),
+                None => json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": {"name": "HANDLER_ERROR", "message": "Unavailable"},
+                }),
+            }
+            .to_string();
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                        response.len()
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+        }
+    });
+    url
+}
+
+#[tokio::test]
+async fn reconciles_balances_with_rpc() {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let url = mock_rpc_server(
+        HashMap::from([("alice.near".to_owned(), 100), ("bob.near".to_owned(), 40)]),
+        requests.clone(),
+    )
+    .await;
+    let take_requests = || std::mem::take(&mut *requests.lock().unwrap());
+
+    let mut tracker = BalanceTracker::new().with_near_balances();
+    let mut reconciler = Reconciler::new(tracker.reader(), RpcClient::new(url), 1);
+    assert_eq!(reconciler.run_once().await.unwrap(), None);
+
+    for (account_id, amount) in [("alice.near", 100), ("bob.near", 50)] {
+        let mint = FtMintEvent {
+            owner_id: account_id.parse().unwrap(),
+            amount,
+            memo: None,
+        };
+        tracker
+            .handle_mint(mint, test_context(10, "token.near"))
+            .await
+            .unwrap();
+    }
+    // NEAR balances are not checked
+    tracker
+        .handle_near_balance_change(near_balance_change("alice.near", 1000, 10))
+        .await
+        .unwrap();
+    tracker.flush_events(10).await.unwrap();
+
+    // One balance is checked per run, in order
+    let report = reconciler.run_once().await.unwrap().unwrap();
+    assert_eq!(report.block_height, 10);
+    assert_eq!(report.checked, 1);
+    assert!(report.mismatches.is_empty());
+
+    let report = reconciler.run_once().await.unwrap().unwrap();
+    let [mismatch] = report.mismatches.as_slice() else {
+        panic!("Expected a single mismatch, got {report:?}");
+    };
+    assert_eq!(mismatch.account_id, "bob.near");
+    assert_eq!(mismatch.indexed_balance, 50);
+    assert_eq!(mismatch.rpc_balance, 40);
+    assert_eq!(mismatch.last_matching_block, None);
+    assert_eq!(mismatch.first_mismatching_block, 10);
+
+    let report = reconciler.run_once().await.unwrap().unwrap();
+    assert!(report.mismatches.is_empty());
+    assert_eq!(
+        take_requests(),
+        vec![
+            (10, "alice.near".to_owned()),
+            (10, "bob.near".to_owned()),
+            (10, "alice.near".to_owned()),
+        ]
+    );
+
+    // A balance that can't be checked is tried again on the next run
+    tracker
+        .handle_mint(
+            FtMintEvent {
+                owner_id: "carol.near".parse().unwrap(),
+                amount: 5,
+                memo: None,
+            },
+            test_context(11, "token.near"),
+        )
+        .await
+        .unwrap();
+    tracker.flush_events(11).await.unwrap();
+    let report = reconciler.run_once().await.unwrap().unwrap();
+    let [mismatch] = report.mismatches.as_slice() else {
+        panic!("Expected a single mismatch, got {report:?}");
+    };
+    assert_eq!(mismatch.account_id, "bob.near");
+    assert_eq!(mismatch.first_mismatching_block, 10);
+    assert!(reconciler.run_once().await.is_err());
+    assert!(reconciler.run_once().await.is_err());
+    assert_eq!(
+        take_requests(),
+        vec![
+            (11, "bob.near".to_owned()),
+            (11, "carol.near".to_owned()),
+            (11, "carol.near".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn parses_mt_events() {
+    let log = r#"EVENT_JSON:{"standard":"nep245","version":"1.0.0","event":"mt_transfer","data":[{"old_owner_id":"alice.near","new_owner_id":"intents.near","token_ids":["nep141:wrap.near","nep141:usdt.tether-token.near"],"amounts":["1000000000000000000000000","5000000"]}]}"#;
+    assert_eq!(
+        MtEventLog::parse(log),
+        Some(MtEventLog::Transfer(vec![MtTransferEvent {
+            old_owner_id: "alice.near".parse().unwrap(),
+            new_owner_id: "intents.near".parse().unwrap(),
+            token_ids: vec![
+                "nep141:wrap.near".to_owned(),
+                "nep141:usdt.tether-token.near".to_owned()
+            ],
+            amounts: vec![1_000_000_000_000_000_000_000_000, 5_000_000],
+            authorized_id: None,
+            memo: None,
+        }]))
+    );
+
+    let mismatched_amounts = r#"EVENT_JSON:{"standard":"nep245","version":"1.0.0","event":"mt_burn","data":[{"owner_id":"alice.near","token_ids":["a","b"],"amounts":["1"]}]}"#;
+    assert_eq!(MtEventLog::parse(mismatched_amounts), None);
+
+    let nep141 = r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_burn","data":[{"owner_id":"alice.near","amount":"1"}]}"#;
+    assert_eq!(MtEventLog::parse(nep141), None);
+}
+
+#[test]
+fn parses_legacy_tkn_logs() {
+    let events = TknTransferLogParser
+        .parse("Transfer 1000 from alice.near to bob.near")
+        .unwrap();
+    let [FtEvent::Transfer(transfer)] = events.as_slice() else {
+        panic!("Expected a single transfer, got {events:?}");
+    };
+    assert_eq!(transfer.old_owner_id, "alice.near");
+    assert_eq!(transfer.new_owner_id, "bob.near");
+    assert_eq!(transfer.amount, 1000);
+
+    assert!(TknTransferLogParser
+        .parse("Transfer lots from alice.near to bob.near")
+        .is_none());
+    assert!(TknTransferLogParser
+        .parse("Transfer 1000 to bob.near")
+        .is_none());
+
+    let scope = ContractScope::Only(vec![
+        "*.tkn.near".parse().unwrap(),
+        "token.near".parse().unwrap(),
+    ]);
+    assert!(scope.contains(&"intel.tkn.near".parse().unwrap()));
+    assert!(scope.contains(&"token.near".parse().unwrap()));
+    assert!(!scope.contains(&"tkn.near".parse().unwrap()));
+    assert!(!scope.contains(&"scam.near".parse().unwrap()));
+}
+
+#[test]
+fn categorizes_deposits() {
+    let rules = DepositCategoryRules::default();
+    let categorize = |contract_id: &str, method_name: &str| {
+        rules.categorize(&contract_id.parse().unwrap(), method_name)
+    };
+    assert_eq!(
+        categorize("usdt.tether-token.near", "storage_deposit"),
+        DepositCategory::StorageDeposit
+    );
+    assert_eq!(
+        categorize("astro-stakers.poolv1.near", "deposit_and_stake"),
+        DepositCategory::Staking
+    );
+    assert_eq!(
+        categorize("astro-stakers.poolv1.near", "deposit"),
+        DepositCategory::Staking
+    );
+    assert_eq!(
+        categorize("wrap.near", "near_deposit"),
+        DepositCategory::Wrap
+    );
+    assert_eq!(
+        categorize("marketplace.paras.near", "buy"),
+        DepositCategory::NftPurchase
+    );
+    assert_eq!(categorize("shop.near", "buy"), DepositCategory::Payment);
+    assert_eq!(
+        categorize("v2.ref-finance.near", "deposit"),
+        DepositCategory::Payment
+    );
+
+    let rules: DepositCategoryRules = serde_json::from_str(
+        r#"[{"method_name": "buy", "contract": "*.market.near", "category": "NftPurchase"}]"#,
+    )
+    .unwrap();
+    assert_eq!(
+        rules.categorize(&"nft.market.near".parse().unwrap(), "buy"),
+        DepositCategory::NftPurchase
+    );
+    assert_eq!(
+        rules.categorize(
+            &"usdt.tether-token.near".parse().unwrap(),
+            "storage_deposit"
+        ),
+        DepositCategory::Payment
+    );
+}
+
+#[test]
+fn filters_events() {
+    let config: FilterConfig = serde_json::from_str(
+        r#"{
+            "indexer": {
+                "allowed_tokens": ["*.sweat", "near"],
+                "denied_accounts": ["spammer.near"],
+                "min_amount": "100"
+            },
+            "streams": {"ft_transfer": {"allowed_accounts": ["alice.near"]}}
+        }"#,
+    )
+    .unwrap();
+    let filter = &config.indexer;
+    let account = |account_id: &str| account_id.parse().unwrap();
+    let (alice, bob, spammer) = (
+        account("alice.near"),
+        account("bob.near"),
+        account("spammer.near"),
+    );
+
+    assert!(filter.allows(&account("token.sweat"), &[&alice, &bob], Some(100)));
+    assert!(filter.allows(&account("near"), &[&alice], None));
+    assert!(!filter.allows(&account("token.sweat"), &[&alice, &bob], Some(99)));
+    assert!(!filter.allows(&account("usdt.tether-token.near"), &[&alice], Some(100)));
+    assert!(!filter.allows(&account("token.sweat"), &[&bob, &spammer], Some(100)));
+
+    let transfer_filter = &config.streams["ft_transfer"];
+    assert!(transfer_filter.allows(&account("token.sweat"), &[&bob, &alice], Some(1)));
+    assert!(!transfer_filter.allows(&account("token.sweat"), &[&bob, &spammer], Some(1)));
+}
+
+#[tokio::test]
+async fn indexes_mt_and_ft_logs_of_a_receipt() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    let receipt = TestReceipt {
+        receiver_id: "intents.near",
+        logs: vec![
+            r#"EVENT_JSON:{"standard":"nep245","version":"1.0.0","event":"mt_transfer","data":[{"old_owner_id":"alice.near","new_owner_id":"bob.near","token_ids":["nep141:wrap.near"],"amounts":["5"]}]}"#.to_owned(),
+            // Mentions NEP-245, but isn't a multi token event
+            r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[{"old_owner_id":"alice.near","new_owner_id":"bob.near","amount":"10","memo":"nep245 migration"}]}"#.to_owned(),
+        ],
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("alice.near", "intents.near", Vec::new(), &[]);
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+
+    let events = take_events(&recorded);
+    let [(RecordedEvent::MtTransfer(mt_transfer), mt_context), (RecordedEvent::Transfer(transfer), context)] =
+        events.as_slice()
+    else {
+        panic!("Expected a multi token transfer and a transfer, got {events:?}");
+    };
+    assert_eq!(mt_transfer.amounts, vec![5]);
+    assert_eq!(mt_context.log_index, Some(0));
+    assert_eq!(transfer.amount, 10);
+    assert_eq!(transfer.memo.as_deref(), Some("nep245 migration"));
+    assert_eq!(context.log_index, Some(1));
+}
+
+fn ft_transfer_log(old_owner_id: &str, new_owner_id: &str, amount: u128) -> String {
+    format!(
+        r#"EVENT_JSON:{{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[{{"old_owner_id":"{old_owner_id}","new_owner_id":"{new_owner_id}","amount":"{amount}"}}]}}"#
+    )
+}
+
+#[tokio::test]
+async fn records_relayer_of_meta_transactions() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    // Receipt with the actions that alice.near delegated
+    let receipt = TestReceipt {
+        actions: vec![transfer_action(3)],
+        logs: vec![ft_transfer_log("alice.near", "bob.near", 10)],
+        ..Default::default()
+    }
+    .build();
+    let meta_transaction = test_transaction(
+        "relayer.near",
+        "alice.near",
+        vec![json!({"Delegate": {
+            "delegate_action": {
+                "sender_id": "alice.near",
+                "receiver_id": "token.near",
+                "actions": [],
+                "nonce": 1,
+                "max_block_height": 1000,
+                "public_key": TEST_PUBLIC_KEY,
+            },
+            "signature": TEST_SIGNATURE,
+        }})],
+        &[],
+    );
+    indexer
+        .process_receipt(&receipt, &meta_transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    assert_eq!(events.len(), 2);
+    for (_, context) in &events {
+        assert_eq!(context.predecessor_id, "alice.near");
+        assert_eq!(
+            context.relayer_id,
+            Some("relayer.near".parse::<AccountId>().unwrap())
+        );
+    }
+    // Receipts created by the delegated actions are a part of it too
+    let callback = TestReceipt {
+        id: "callback",
+        predecessor_id: "token.near",
+        receiver_id: "bob.near",
+        actions: vec![transfer_action(1)],
+        ..Default::default()
+    }
+    .build();
+    indexer
+        .process_receipt(&callback, &meta_transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0].1.relayer_id,
+        Some("relayer.near".parse::<AccountId>().unwrap())
+    );
+
+    let transaction = test_transaction("alice.near", "token.near", Vec::new(), &[]);
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    assert_eq!(events.len(), 2);
+    assert!(events
+        .iter()
+        .all(|(_, context)| context.relayer_id.is_none()));
+}
+
+#[tokio::test]
+async fn detects_account_deletion_transfers() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    let delete = TestReceipt {
+        id: "delete",
+        receiver_id: "alice.near",
+        actions: vec![json!({"DeleteAccount": {"beneficiary_id": "bob.near"}})],
+        receipt_ids: vec!["balance_refund", "gas_refund"],
+        ..Default::default()
+    }
+    .build();
+    // The balance is sent in a refund signed by `system`, in a later block
+    let balance_refund = TestReceipt {
+        id: "balance_refund",
+        predecessor_id: "system",
+        receiver_id: "bob.near",
+        signer_id: "system",
+        actions: vec![transfer_action(5_000)],
+        ..Default::default()
+    }
+    .build();
+    // Gas refunds are signed by their receiver
+    let gas_refund = TestReceipt {
+        id: "gas_refund",
+        predecessor_id: "system",
+        receiver_id: "bob.near",
+        signer_id: "bob.near",
+        actions: vec![transfer_action(7)],
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("bob.near", "alice.near", Vec::new(), &[&delete]);
+
+    indexer
+        .process_receipt(&delete, &transaction)
+        .await
+        .unwrap();
+    assert!(take_events(&recorded).is_empty());
+    indexer
+        .process_receipt(&balance_refund, &transaction)
+        .await
+        .unwrap();
+    indexer
+        .process_receipt(&gas_refund, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Transfer(transfer), context)] = events.as_slice() else {
+        panic!("Expected a single transfer, got {events:?}");
+    };
+    assert_eq!(transfer.old_owner_id, "alice.near");
+    assert_eq!(transfer.new_owner_id, "bob.near");
+    assert_eq!(transfer.amount, 5_000);
+    assert_eq!(context.contract_id, "near");
+    assert_eq!(
+        context.native_transfer,
+        Some(NativeTransferKind::AccountDeletion)
+    );
+    assert_eq!(context.receipt_id, test_hash("balance_refund"));
+
+    // A balance refund of a receipt that didn't delete an account is not a transfer
+    let other_transaction = test_transaction("bob.near", "alice.near", Vec::new(), &[]);
+    indexer
+        .process_receipt(&balance_refund, &other_transaction)
+        .await
+        .unwrap();
+    assert!(take_events(&recorded).is_empty());
+}
+
+#[test]
+fn tracks_near_balance_before_changes() {
+    let account_update = |account_id: &str, amount: u128| -> StateChangeWithCauseView {
+        serde_json::from_value(json!({
+            "cause": {"type": "receipt_processing", "receipt_hash": test_hash("receipt")},
+            "type": "account_update",
+            "change": {
+                "account_id": account_id,
+                "amount": amount.to_string(),
+                "locked": "0",
+                "code_hash": CryptoHash::default(),
+                "storage_usage": 100,
+                "storage_paid_at": 0,
+            },
+        }))
+        .unwrap()
+    };
+    let account_deletion = |account_id: &str| -> StateChangeWithCauseView {
+        serde_json::from_value(json!({
+            "cause": {"type": "receipt_processing", "receipt_hash": test_hash("receipt")},
+            "type": "account_deletion",
+            "change": {"account_id": account_id},
+        }))
+        .unwrap()
+    };
+    let summary = |changes: Vec<NearBalanceChange>| {
+        changes
+            .into_iter()
+            .map(|change| {
+                (
+                    change.account_id.to_string(),
+                    change.balance_before,
+                    change.balance_after,
+                    change.deleted,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut balance_changes = NearBalanceChanges::new();
+    let changes = balance_changes.process_state_changes(
+        10,
+        0,
+        &[
+            account_update("alice.near", 100),
+            account_update("alice.near", 90),
+            account_update("bob.near", 5),
+        ],
+    );
+    assert_eq!(
+        summary(changes),
+        vec![
+            ("alice.near".to_owned(), None, 100, false),
+            ("alice.near".to_owned(), Some(100), 90, false),
+            ("bob.near".to_owned(), None, 5, false),
+        ]
+    );
+
+    // Balances before the first change in a block come from earlier blocks
+    let changes = balance_changes.process_state_changes(
+        11,
+        0,
+        &[
+            account_update("alice.near", 80),
+            account_update("bob.near", 5),
+            account_deletion("alice.near"),
+        ],
+    );
+    let changes = summary(changes);
+    assert_eq!(
+        changes,
+        vec![
+            ("alice.near".to_owned(), Some(90), 80, false),
+            ("alice.near".to_owned(), Some(80), 0, true),
+        ]
+    );
+
+    // The balance of a deleted account is not kept
+    let changes = balance_changes.process_state_changes(12, 0, &[account_update("alice.near", 7)]);
+    assert_eq!(
+        summary(changes),
+        vec![("alice.near".to_owned(), None, 7, false)]
+    );
+
+    // Only the last balances of the most recently changed accounts are kept
+    let mut balance_changes = NearBalanceChanges::with_capacity(2);
+    balance_changes.process_state_changes(
+        10,
+        0,
+        &[
+            account_update("alice.near", 1),
+            account_update("bob.near", 2),
+            account_update("alice.near", 3),
+            account_update("carol.near", 4),
+        ],
+    );
+    let changes = balance_changes.process_state_changes(
+        11,
+        0,
+        &[
+            account_update("alice.near", 5),
+            account_update("bob.near", 6),
+        ],
+    );
+    assert_eq!(
+        summary(changes),
+        vec![
+            ("alice.near".to_owned(), Some(3), 5, false),
+            ("bob.near".to_owned(), None, 6, false),
+        ]
+    );
+}
+
+fn function_call(method_name: &str, args: serde_json::Value, deposit: u128) -> serde_json::Value {
+    json!({"FunctionCall": {
+        "method_name": method_name,
+        "args": BASE64.encode(args.to_string()),
+        "gas": 30_000_000_000_000u64,
+        "deposit": deposit.to_string(),
+    }})
+}
+
+fn account_does_not_exist(account_id: &str) -> serde_json::Value {
+    json!({"Failure": {"ActionError": {
+        "index": 0,
+        "kind": {"AccountDoesNotExist": {"account_id": account_id}},
+    }}})
+}
+
+#[tokio::test]
+async fn indexes_events_of_failed_receipts_as_reverted() {
+    let receipt = TestReceipt {
+        actions: vec![function_call("buy", json!({}), 50)],
+        logs: vec![ft_transfer_log("alice.near", "bob.near", 10)],
+        status: account_does_not_exist("token.near"),
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("alice.near", "token.near", Vec::new(), &[]);
+
+    // Not indexed by default
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+    assert!(take_events(&recorded).is_empty());
+
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions {
+        index_reverted_events: true,
+        ..Default::default()
+    });
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::RevertedTransfer(transfer, error), context), (RecordedEvent::RevertedTransfer(deposit, deposit_error), deposit_context)] =
+        events.as_slice()
+    else {
+        panic!("Expected two reverted transfers, got {events:?}");
+    };
+    assert_eq!(transfer.amount, 10);
+    assert_eq!(context.contract_id, "token.near");
+    assert_eq!(deposit.amount, 50);
+    assert_eq!(deposit_context.contract_id, "near");
+    assert_eq!(
+        deposit_context.native_transfer,
+        Some(NativeTransferKind::FunctionCallDeposit)
+    );
+    let expected_error: TxExecutionError = serde_json::from_value(json!({"ActionError": {
+        "index": 0,
+        "kind": {"AccountDoesNotExist": {"account_id": "token.near"}},
+    }}))
+    .unwrap();
+    assert_eq!(error, &expected_error);
+    assert_eq!(deposit_error, &expected_error);
+}
+
+fn success_value(value: serde_json::Value) -> serde_json::Value {
+    json!({"SuccessValue": BASE64.encode(value.to_string())})
+}
+
+#[tokio::test]
+async fn links_refunds_to_transfer_calls() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions {
+        emit_effective_transfers: true,
+        ..Default::default()
+    });
+    let transfer_call = |id, resolve_id| {
+        TestReceipt {
+            id,
+            actions: vec![function_call(
+                "ft_transfer_call",
+                json!({"receiver_id": "pool.near", "amount": "100", "msg": "swap"}),
+                1,
+            )],
+            logs: vec![ft_transfer_log("alice.near", "pool.near", 100)],
+            receipt_ids: vec!["on_transfer", resolve_id],
+            ..Default::default()
+        }
+        .build()
+    };
+    // An identical transfer earlier in the same transaction, resolved separately
+    let other_transfer_call = transfer_call("other_transfer_call", "other_resolve");
+    let transfer_call = transfer_call("transfer_call", "resolve");
+    let resolve = |predecessor_id| {
+        TestReceipt {
+            id: "resolve",
+            predecessor_id,
+            actions: vec![function_call(
+                "ft_resolve_transfer",
+                json!({"sender_id": "alice.near", "receiver_id": "pool.near", "amount": "100"}),
+                0,
+            )],
+            logs: vec![ft_transfer_log("pool.near", "alice.near", 40)],
+            status: success_value(json!("60")),
+            ..Default::default()
+        }
+        .build()
+    };
+    let fake_resolve = resolve("pool.near");
+    let resolve = resolve("token.near");
+    let transaction = test_transaction(
+        "alice.near",
+        "token.near",
+        Vec::new(),
+        &[&other_transfer_call, &transfer_call, &resolve],
+    );
+
+    indexer
+        .process_receipt(&resolve, &transaction)
+        .await
+        .unwrap();
+    let transfer_event_id = format!("{}-log0-0", test_hash("transfer_call"));
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Transfer(refund), refund_context), (RecordedEvent::EffectiveTransfer(effective), _)] =
+        events.as_slice()
+    else {
+        panic!("Expected a refund and an effective transfer, got {events:?}");
+    };
+    assert_eq!(refund.amount, 40);
+    assert_eq!(refund_context.refund_of, Some(transfer_event_id.clone()));
+    assert_eq!(effective.old_owner_id, "alice.near");
+    assert_eq!(effective.new_owner_id, "pool.near");
+    assert_eq!(effective.amount, 60);
+    assert_eq!(effective.transferred_amount, 100);
+    assert_eq!(effective.transfer_event_id, Some(transfer_event_id));
+
+    // Only the token contract can resolve its transfers
+    indexer
+        .process_receipt(&fake_resolve, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Transfer(_), context)] = events.as_slice() else {
+        panic!("Expected a single transfer, got {events:?}");
+    };
+    assert_eq!(context.refund_of, None);
+}
+
+#[tokio::test]
+async fn adds_transfer_call_context_to_transfers() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    let transfer_call = TestReceipt {
+        id: "transfer_call",
+        actions: vec![function_call(
+            "ft_transfer_call",
+            json!({"receiver_id": "pool.near", "amount": "100", "memo": "order 1", "msg": "swap"}),
+            1,
+        )],
+        logs: vec![
+            ft_transfer_log("alice.near", "pool.near", 100),
+            // A fee taken by the token, not sent to the receiver of the call
+            ft_transfer_log("alice.near", "fees.near", 1),
+        ],
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("alice.near", "token.near", Vec::new(), &[]);
+    indexer
+        .process_receipt(&transfer_call, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Transfer(transfer), context), (RecordedEvent::Transfer(fee), fee_context)] =
+        events.as_slice()
+    else {
+        panic!("Expected two transfers, got {events:?}");
+    };
+    assert_eq!(transfer.new_owner_id, "pool.near");
+    assert_eq!(context.method_name.as_deref(), Some("ft_transfer_call"));
+    assert_eq!(
+        context.transfer_call,
+        Some(TransferCallContext {
+            receiver_id: "pool.near".parse().unwrap(),
+            msg: "swap".to_owned(),
+            memo: Some("order 1".to_owned()),
+            receiver_method: "ft_on_transfer".to_owned(),
+        })
+    );
+    assert_eq!(fee.new_owner_id, "fees.near");
+    assert_eq!(fee_context.method_name.as_deref(), Some("ft_transfer_call"));
+    assert_eq!(fee_context.transfer_call, None);
+
+    // Other methods have no transfer call context
+    let transfer = TestReceipt {
+        actions: vec![function_call(
+            "ft_transfer",
+            json!({"receiver_id": "bob.near", "amount": "10"}),
+            1,
+        )],
+        logs: vec![ft_transfer_log("alice.near", "bob.near", 10)],
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("alice.near", "token.near", Vec::new(), &[]);
+    indexer
+        .process_receipt(&transfer, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Transfer(_), context)] = events.as_slice() else {
+        panic!("Expected a single transfer, got {events:?}");
+    };
+    assert_eq!(context.method_name.as_deref(), Some("ft_transfer"));
+    assert_eq!(context.transfer_call, None);
+}
+
+#[tokio::test]
+async fn links_near_and_wnear_legs_of_wrapping() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    let deposit = TestReceipt {
+        receiver_id: "wrap.near",
+        actions: vec![function_call("near_deposit", json!({}), 1_000)],
+        logs: vec![r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_mint","data":[{"owner_id":"alice.near","amount":"1000"}]}"#.to_owned()],
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("alice.near", "wrap.near", Vec::new(), &[]);
+    indexer
+        .process_receipt(&deposit, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Mint(_), mint_context), (RecordedEvent::Transfer(_), near_context), (RecordedEvent::NearWrap(wrap), _)] =
+        events.as_slice()
+    else {
+        panic!("Expected a mint, a transfer and a wrap, got {events:?}");
+    };
+    assert_eq!(wrap.kind, WrapKind::Wrap);
+    assert_eq!(wrap.account_id, "alice.near");
+    assert_eq!(wrap.amount, 1_000);
+    assert_eq!(near_context.contract_id, "near");
+    assert_eq!(wrap.near_event_id, Some(near_context.event_id()));
+    assert_eq!(wrap.wnear_event_id, Some(mint_context.event_id()));
+
+    let withdraw = TestReceipt {
+        receiver_id: "wrap.near",
+        actions: vec![function_call("near_withdraw", json!({"amount": "500"}), 1)],
+        logs: vec![r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_burn","data":[{"owner_id":"alice.near","amount":"500"}]}"#.to_owned()],
+        receipt_ids: vec!["gas_refund", "withdrawal"],
+        ..Default::default()
+    }
+    .build();
+    let withdrawal = TestReceipt {
+        id: "withdrawal",
+        predecessor_id: "wrap.near",
+        receiver_id: "alice.near",
+        actions: vec![transfer_action(500)],
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("alice.near", "wrap.near", Vec::new(), &[&withdrawal]);
+    indexer
+        .process_receipt(&withdraw, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Burn(_), burn_context), (RecordedEvent::NearWrap(unwrap), _)] =
+        events.as_slice()
+    else {
+        panic!("Expected a burn and an unwrap, got {events:?}");
+    };
+    assert_eq!(unwrap.kind, WrapKind::Unwrap);
+    assert_eq!(unwrap.account_id, "alice.near");
+    assert_eq!(unwrap.amount, 500);
+    assert_eq!(unwrap.wnear_event_id, Some(burn_context.event_id()));
+
+    // The NEAR leg is the transfer in the receipt created by `near_withdraw`
+    indexer
+        .process_receipt(&withdrawal, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Transfer(transfer), near_context)] = events.as_slice() else {
+        panic!("Expected a single transfer, got {events:?}");
+    };
+    assert_eq!(transfer.amount, 500);
+    assert_eq!(unwrap.near_event_id, Some(near_context.event_id()));
+}
+
+#[tokio::test]
+async fn parses_legacy_logs_only_of_tkn_tokens() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    for (contract_id, expected_transfers) in [("intel.tkn.near", 1), ("scam.near", 0)] {
+        let receipt = TestReceipt {
+            receiver_id: contract_id,
+            logs: vec!["Transfer 1000 from alice.near to bob.near".to_owned()],
+            ..Default::default()
+        }
+        .build();
+        let transaction = test_transaction("alice.near", contract_id, Vec::new(), &[]);
+        indexer
+            .process_receipt(&receipt, &transaction)
+            .await
+            .unwrap();
+        assert_eq!(
+            take_events(&recorded).len(),
+            expected_transfers,
+            "{contract_id}"
+        );
+    }
+
+    // Other contracts can be added with their own scope
+    indexer.register_legacy_log_parser(
+        TknTransferLogParser,
+        ContractScope::Only(vec!["legacy.near".parse().unwrap()]),
+    );
+    let receipt = TestReceipt {
+        receiver_id: "legacy.near",
+        logs: vec!["Transfer 1000 from alice.near to bob.near".to_owned()],
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("alice.near", "legacy.near", Vec::new(), &[]);
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Transfer(transfer), _)] = events.as_slice() else {
+        panic!("Expected a single transfer, got {events:?}");
+    };
+    assert_eq!(transfer.amount, 1000);
+}
+
+#[tokio::test]
+async fn suppresses_legacy_logs_duplicated_by_nep141_events() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    let receipt = TestReceipt {
+        receiver_id: "intel.tkn.near",
+        logs: vec![
+            "Transfer 1000 from alice.near to bob.near".to_owned(),
+            ft_transfer_log("alice.near", "bob.near", 1000),
+            // The same transfer again, but only one standard event matches it
+            "Transfer 1000 from alice.near to bob.near".to_owned(),
+            "Transfer 5 from alice.near to carol.near".to_owned(),
+        ],
+        ..Default::default()
+    }
+    .build();
+    let transaction = test_transaction("alice.near", "intel.tkn.near", Vec::new(), &[]);
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+
+    let events = take_events(&recorded);
+    let transfers: Vec<_> = events
+        .iter()
+        .map(|(event, context)| {
+            let RecordedEvent::Transfer(transfer) = event else {
+                panic!("Expected only transfers, got {events:?}");
+            };
+            (
+                transfer.new_owner_id.to_string(),
+                transfer.amount,
+                context.log_index,
+            )
+        })
+        .collect();
+    assert_eq!(
+        transfers,
+        vec![
+            ("bob.near".to_owned(), 1000, Some(1)),
+            ("bob.near".to_owned(), 1000, Some(2)),
+            ("carol.near".to_owned(), 5, Some(3)),
+        ]
+    );
+    assert_eq!(indexer.suppressed_duplicates(), 1);
+}
+
+#[tokio::test]
+async fn verifies_token_contracts() {
+    let receipt = |contract_id| {
+        TestReceipt {
+            receiver_id: contract_id,
+            actions: vec![function_call(
+                "ft_transfer",
+                json!({"receiver_id": "bob.near", "amount": "10"}),
+                5,
+            )],
+            logs: vec![ft_transfer_log("alice.near", "bob.near", 10)],
+            ..Default::default()
+        }
+        .build()
+    };
+    let verification = |policy| FtIndexerOptions {
+        token_verification: Some(TokenVerificationOptions {
+            policy,
+            known_tokens: ["token.near".parse().unwrap()].into(),
+            trust_transfer_calls: true,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    // Token events of the receipt, and whether they are verified
+    let token_events = |events: Vec<(RecordedEvent, EventContext)>| {
+        events
+            .into_iter()
+            .filter(|(_, context)| context.native_transfer.is_none())
+            .map(|(_, context)| context.token_verified)
+            .collect::<Vec<_>>()
+    };
+
+    let (mut indexer, recorded) = recording_indexer(verification(UnverifiedTokenPolicy::Flag));
+    for (contract_id, expected) in [
+        ("token.near", vec![Some(true)]),
+        ("scam.near", vec![Some(false)]),
+        // Verified by the `ft_transfer` call in the previous receipt
+        ("scam.near", vec![Some(true)]),
+    ] {
+        let transaction = test_transaction("alice.near", contract_id, Vec::new(), &[]);
+        indexer
+            .process_receipt(&receipt(contract_id), &transaction)
+            .await
+            .unwrap();
+        let events = take_events(&recorded);
+        // Native deposits are not token events, so they are never verified
+        assert!(events.iter().any(|(_, context)| {
+            context.native_transfer == Some(NativeTransferKind::FunctionCallDeposit)
+                && context.token_verified.is_none()
+        }));
+        assert_eq!(token_events(events), expected, "{contract_id}");
+    }
+
+    let (mut indexer, recorded) = recording_indexer(verification(UnverifiedTokenPolicy::Drop));
+    for (contract_id, expected) in [
+        ("token.near", vec![Some(true)]),
+        ("scam.near", vec![]),
+        ("scam.near", vec![Some(true)]),
+    ] {
+        let transaction = test_transaction("alice.near", contract_id, Vec::new(), &[]);
+        indexer
+            .process_receipt(&receipt(contract_id), &transaction)
+            .await
+            .unwrap();
+        let events = take_events(&recorded);
+        assert_eq!(events.len(), expected.len() + 1, "{contract_id}");
+        assert_eq!(token_events(events), expected, "{contract_id}");
+    }
+}
+
+#[tokio::test]
+async fn discovers_tokens() {
+    let dir = test_dir("token_registry");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("tokens.json");
+    let discovered = Arc::new(Mutex::new(Vec::new()));
+    let discovering_indexer = |token_registry| {
+        let discovered = discovered.clone();
+        let handler = Filter::new(
+            NoopHandler,
+            move |event: &HandlerEvent, _context: &EventContext| {
+                if let HandlerEvent::TokenDiscovered(token) = event {
+                    discovered.lock().unwrap().push(token.clone());
+                }
+                false
+            },
+        );
+        FtIndexer::new(handler).with_token_registry(token_registry)
+    };
+    let take_discovered = || std::mem::take(&mut *discovered.lock().unwrap());
+    let transfer = |contract_id, block_height| {
+        let mut receipt = TestReceipt {
+            receiver_id: contract_id,
+            logs: vec![ft_transfer_log("alice.near", "bob.near", 10)],
+            ..Default::default()
+        }
+        .build();
+        receipt.block_height = block_height;
+        receipt
+    };
+    let transaction = test_transaction("alice.near", "token.near", Vec::new(), &[]);
+    let token: AccountId = "token.near".parse().unwrap();
+
+    let mut indexer = discovering_indexer(TokenRegistry::open(&path, 10).unwrap());
+    for _ in 0..2 {
+        indexer
+            .process_receipt(&transfer("token.near", 100), &transaction)
+            .await
+            .unwrap();
+    }
+    let discovered_tokens = take_discovered();
+    let [token_discovered] = discovered_tokens.as_slice() else {
+        panic!("Expected a single discovered token, got {discovered_tokens:?}");
+    };
+    assert_eq!(token_discovered.token_id, token);
+    assert_eq!(token_discovered.first_block_height, 100);
+    assert_eq!(token_discovered.first_event_kind, FtEventKind::Transfer);
+    assert_eq!(
+        token_discovered.first_event_id,
+        format!("{}-log0-0", test_hash("receipt"))
+    );
+    assert_eq!(indexer.token_registry().get(&token).unwrap().event_count, 2);
+    indexer.finish_block(100).await.unwrap();
+
+    // Blocks that were already saved are not counted again after a restart
+    let mut indexer = discovering_indexer(TokenRegistry::open(&path, 10).unwrap());
+    let stats = indexer.token_registry().get(&token).unwrap();
+    assert_eq!(stats.event_count, 2);
+    assert_eq!(stats.last_block_height, 100);
+    indexer
+        .process_receipt(&transfer("token.near", 100), &transaction)
+        .await
+        .unwrap();
+    indexer.finish_block(100).await.unwrap();
+    assert_eq!(indexer.token_registry().get(&token).unwrap().event_count, 2);
+
+    indexer
+        .process_receipt(&transfer("token.near", 101), &transaction)
+        .await
+        .unwrap();
+    indexer
+        .process_receipt(&transfer("other.near", 101), &transaction)
+        .await
+        .unwrap();
+    indexer.finish_block(101).await.unwrap();
+    let discovered_tokens = take_discovered();
+    let [token_discovered] = discovered_tokens.as_slice() else {
+        panic!("Expected a single discovered token, got {discovered_tokens:?}");
+    };
+    assert_eq!(token_discovered.token_id, "other.near");
+    let registry = TokenRegistry::open(&path, 10).unwrap();
+    let stats = registry.get(&token).unwrap();
+    assert_eq!(stats.event_count, 3);
+    assert_eq!(stats.first_block_height, 100);
+    assert_eq!(stats.last_block_height, 101);
+    assert_eq!(registry.tokens().len(), 2);
+
+    // Changed counters are only saved after the interval
+    let saved_event_count = || {
+        TokenRegistry::open(&path, 10)
+            .unwrap()
+            .get(&token)
+            .unwrap()
+            .event_count
+    };
+    indexer
+        .process_receipt(&transfer("token.near", 102), &transaction)
+        .await
+        .unwrap();
+    indexer.finish_block(102).await.unwrap();
+    assert_eq!(saved_event_count(), 3);
+    indexer
+        .process_receipt(&transfer("token.near", 111), &transaction)
+        .await
+        .unwrap();
+    indexer.finish_block(111).await.unwrap();
+    assert_eq!(saved_event_count(), 5);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn detects_token_deployments() {
+    let known_code_hash = CryptoHash::hash_bytes(b"ft contract");
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions {
+        token_deployments: TokenDeploymentOptions {
+            known_code_hashes: [known_code_hash].into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    // Receipt views contain the hash of the code instead of the code
+    let deploy = |contract_id, code_hash: CryptoHash| {
+        TestReceipt {
+            receiver_id: contract_id,
+            actions: vec![
+                transfer_action(0),
+                json!({"DeployContract": {"code": BASE64.encode(code_hash)}}),
+            ],
+            ..Default::default()
+        }
+        .build()
+    };
+    let other_code_hash = CryptoHash::hash_bytes(b"other contract");
+
+    for (contract_id, code_hash, matched_by) in [
+        ("ft.near", known_code_hash, Some(DeploymentMatch::CodeHash)),
+        (
+            "meme.tkn.near",
+            other_code_hash,
+            Some(DeploymentMatch::Factory),
+        ),
+        ("app.near", other_code_hash, None),
+    ] {
+        let transaction = test_transaction("alice.near", contract_id, Vec::new(), &[]);
+        indexer
+            .process_receipt(&deploy(contract_id, code_hash), &transaction)
+            .await
+            .unwrap();
+        let deployments: Vec<_> = take_events(&recorded)
+            .into_iter()
+            .filter_map(|(event, context)| match event {
+                RecordedEvent::TokenDeployed(token) => Some((token, context)),
+                _ => None,
+            })
+            .collect();
+        match matched_by {
+            Some(matched_by) => {
+                let [(token, context)] = deployments.as_slice() else {
+                    panic!("Expected a single deployment to {contract_id}, got {deployments:?}");
+                };
+                assert_eq!(token.token_id, contract_id);
+                assert_eq!(token.code_hash, code_hash);
+                assert_eq!(token.matched_by, matched_by);
+                assert_eq!(context.action_index, Some(1));
+            }
+            None => assert!(deployments.is_empty(), "{contract_id}"),
+        }
+    }
+}
+
+fn ft_burn_log(amounts: &[u128]) -> String {
+    let data: Vec<_> = amounts
+        .iter()
+        .map(|amount| json!({"owner_id": "alice.near", "amount": amount.to_string()}))
+        .collect();
+    format!(
+        "EVENT_JSON:{}",
+        json!({"standard": "nep141", "version": "1.0.0", "event": "ft_burn", "data": data})
+    )
+}
+
+#[tokio::test]
+async fn detects_storage_management() {
+    let (mut indexer, recorded) = recording_indexer(FtIndexerOptions::default());
+    let storage_balance = StorageBalance {
+        total: 1_250_000,
+        available: 0,
+    };
+    let transaction = test_transaction("alice.near", "token.near", Vec::new(), &[]);
+
+    // Deposit paid by alice.near for bob.near
+    let receipt = TestReceipt {
+        actions: vec![function_call(
+            "storage_deposit",
+            json!({"account_id": "bob.near"}),
+            1_250_000,
+        )],
+        status: success_value(json!({"total": "1250000", "available": "0"})),
+        ..Default::default()
+    }
+    .build();
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [(RecordedEvent::Transfer(_), deposit_context), (RecordedEvent::Storage(storage), _)] =
+        events.as_slice()
+    else {
+        panic!("Expected a deposit and a storage event, got {events:?}");
+    };
+    assert_eq!(storage.kind, StorageEventKind::Deposit);
+    assert_eq!(storage.account_id, "bob.near");
+    assert_eq!(storage.amount, Some(1_250_000));
+    assert_eq!(storage.storage_balance, Some(storage_balance.clone()));
+    assert!(!storage.already_registered);
+    assert_eq!(storage.deposit_event_id, Some(deposit_context.event_id()));
+
+    // Deposit for an account that is already registered
+    let receipt = TestReceipt {
+        actions: vec![function_call("storage_deposit", json!({}), 1_250_000)],
+        logs: vec!["The account is already registered, refunding the deposit".to_owned()],
+        status: success_value(json!({"total": "1250000", "available": "0"})),
+        ..Default::default()
+    }
+    .build();
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+    let events = take_events(&recorded);
+    let [_, (RecordedEvent::Storage(storage), _)] = events.as_slice() else {
+        panic!("Expected a deposit and a storage event, got {events:?}");
+    };
+    assert_eq!(storage.account_id, "alice.near");
+    assert!(storage.already_registered);
+    assert_eq!(storage.storage_balance, Some(storage_balance));
+
+    // Forced unregister burns the remaining balance
+    for (logs, burned_amount) in [
+        (vec![ft_burn_log(&[100, 200])], 300),
+        (vec![ft_burn_log(&[u128::MAX, 1])], u128::MAX),
+        // Older versions of `near-contract-standards` only log it
+        (vec!["Closed @alice.near with 500".to_owned()], 500),
+    ] {
+        let receipt = TestReceipt {
+            actions: vec![function_call(
+                "storage_unregister",
+                json!({"force": true}),
+                1,
+            )],
+            logs,
+            status: success_value(json!(true)),
+            ..Default::default()
+        }
+        .build();
+        indexer
+            .process_receipt(&receipt, &transaction)
+            .await
+            .unwrap();
+        let events = take_events(&recorded);
+        let Some((RecordedEvent::Storage(storage), _)) = events.last() else {
+            panic!("Expected a storage event, got {events:?}");
+        };
+        assert_eq!(storage.kind, StorageEventKind::Unregister);
+        assert_eq!(storage.account_id, "alice.near");
+        assert!(storage.force);
+        assert_eq!(storage.burned_amount, Some(burned_amount));
+    }
+
+    // Nothing happens if the account wasn't registered
+    let receipt = TestReceipt {
+        actions: vec![function_call("storage_unregister", json!({}), 1)],
+        status: success_value(json!(false)),
+        ..Default::default()
+    }
+    .build();
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+    assert!(take_events(&recorded).is_empty());
+
+    // Storage events of contracts that are not known tokens are verified
+    // like their token events
+    let receipt = TestReceipt {
+        actions: vec![function_call("storage_deposit", json!({}), 1_250_000)],
+        status: success_value(json!({"total": "1250000", "available": "0"})),
+        ..Default::default()
+    }
+    .build();
+    for (policy, expected_storage_events) in [
+        (UnverifiedTokenPolicy::Flag, 1),
+        (UnverifiedTokenPolicy::Drop, 0),
+    ] {
+        let (mut indexer, recorded) = recording_indexer(FtIndexerOptions {
+            token_verification: Some(TokenVerificationOptions {
+                policy,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        indexer
+            .process_receipt(&receipt, &transaction)
+            .await
+            .unwrap();
+        let storage_contexts: Vec<_> = take_events(&recorded)
+            .into_iter()
+            .filter_map(|(event, context)| {
+                matches!(event, RecordedEvent::Storage(_)).then_some(context)
+            })
+            .collect();
+        assert_eq!(storage_contexts.len(), expected_storage_events);
+        assert!(storage_contexts
+            .iter()
+            .all(|context| context.token_verified == Some(false)));
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a disposable Redis at $REDIS_URL"]
+async fn writes_reverted_events_to_separate_streams() {
+    let client = redis::Client::open(
+        std::env::var("REDIS_URL").expect("No $REDIS_URL environment variable set"),
+    )
+    .unwrap();
+    let mut connection = redis::aio::ConnectionManager::new(client).await.unwrap();
+    let checkpoint_key = "ft_indexer_test_reverted_last_block";
+    let _: () = redis::cmd("DEL")
+        .arg(checkpoint_key)
+        .arg(FT_TRANSFER_REVERTED_STREAM)
+        .arg("ft_transfer")
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    let error: TxExecutionError = serde_json::from_value(json!({"ActionError": {
+        "index": 0,
+        "kind": {"AccountDoesNotExist": {"account_id": "token.near"}},
+    }}))
+    .unwrap();
+    let transfer = FtTransferEvent {
+        old_owner_id: "alice.near".parse().unwrap(),
+        new_owner_id: "bob.near".parse().unwrap(),
+        amount: 10,
+        memo: None,
+    };
+
+    let mut handler = PushToRedisStream::new(connection.clone(), 100)
+        .await
+        .with_checkpoint_key(checkpoint_key);
+    handler
+        .handle_reverted_transfer(transfer, test_context(10, "token.near"), error.clone())
+        .await
+        .unwrap();
+    handler.flush_events(10).await.unwrap();
+
+    let entries = |stream: &'static str| {
+        let mut connection = connection.clone();
+        async move {
+            let entries: Vec<(String, Vec<String>)> = redis::cmd("XRANGE")
+                .arg(stream)
+                .arg("-")
+                .arg("+")
+                .query_async(&mut connection)
+                .await
+                .unwrap();
+            entries
+        }
+    };
+    assert!(entries("ft_transfer").await.is_empty());
+    let reverted = entries(FT_TRANSFER_REVERTED_STREAM).await;
+    let [(id, fields)] = reverted.as_slice() else {
+        panic!("Expected a single reverted transfer, got {reverted:?}");
+    };
+    assert_eq!(id, "10-0");
+    let event: serde_json::Value = serde_json::from_str(&fields[1]).unwrap();
+    assert_eq!(event["amount"], "10");
+    assert_eq!(event["contract_id"], "token.near");
+    assert_eq!(event["error"], serde_json::to_value(&error).unwrap());
+}
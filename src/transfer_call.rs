@@ -0,0 +1,163 @@
+//! Linking of the refund made in `ft_resolve_transfer` to the transfer made by
+//! `ft_transfer_call`
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use inindexer::near_indexer_primitives::views::{ActionView, ExecutionStatusView, ReceiptEnumView};
+use inindexer::near_utils::{EventLogData, FtTransferLog};
+use inindexer::{IncompleteTransaction, TransactionReceipt};
+use serde::{Deserialize, Serialize};
+
+use crate::log_event_id;
+use crate::serde_utils::dec_format;
+
+/// Net result of an `ft_transfer_call`: the amount that the receiver kept
+/// after the refund
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FtEffectiveTransferEvent {
+    pub old_owner_id: AccountId,
+    pub new_owner_id: AccountId,
+    #[serde(with = "dec_format")]
+    pub amount: u128,
+    /// Amount of the original transfer, before the refund
+    #[serde(with = "dec_format")]
+    pub transferred_amount: u128,
+    /// [`EventContext::event_id`](crate::EventContext::event_id) of the original transfer
+    pub transfer_event_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ResolveTransferArgs {
+    pub sender_id: AccountId,
+    pub receiver_id: AccountId,
+    #[serde(with = "dec_format")]
+    pub amount: u128,
+}
+
+/// Returns a function call made to the receiver of the receipt
+pub(crate) fn find_function_call<'a>(
+    receipt: &'a TransactionReceipt,
+    method: &str,
+) -> Option<&'a [u8]> {
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return None;
+    };
+    actions.iter().find_map(|action| match action {
+        ActionView::FunctionCall {
+            method_name, args, ..
+        } if method_name == method => Some(&args[..]),
+        _ => None,
+    })
+}
+
+/// Arguments of `ft_resolve_transfer` if the receipt is this callback. It's
+/// only accepted from the token contract itself.
+pub(crate) fn get_resolve_transfer_args(
+    receipt: &TransactionReceipt,
+) -> Option<ResolveTransferArgs> {
+    if receipt.receipt.receipt.predecessor_id != receipt.receipt.receipt.receiver_id {
+        return None;
+    }
+    serde_json::from_slice(find_function_call(receipt, "ft_resolve_transfer")?).ok()
+}
+
+/// Amount returned by `ft_resolve_transfer`, which is the amount that was not
+/// refunded to the sender
+pub(crate) fn get_used_amount(receipt: &TransactionReceipt) -> Option<u128> {
+    let ExecutionStatusView::SuccessValue(value) =
+        &receipt.receipt.execution_outcome.outcome.status
+    else {
+        return None;
+    };
+    serde_json::from_slice::<String>(value).ok()?.parse().ok()
+}
+
+/// Finds the transfer event emitted by the `ft_transfer_call` receipt that
+/// created the `ft_resolve_transfer` receipt `resolve_receipt`
+pub(crate) fn find_transfer_call_event_id(
+    transaction: &IncompleteTransaction,
+    resolve_receipt: &TransactionReceipt,
+    args: &ResolveTransferArgs,
+) -> Option<String> {
+    let resolve_receipt_id = &resolve_receipt.receipt.receipt.receipt_id;
+    let contract_id = &resolve_receipt.receipt.receipt.receiver_id;
+    for transfer_call_receipt in transaction.receipts.values().flatten() {
+        if &transfer_call_receipt.receipt.receipt.receiver_id != contract_id
+            || !transfer_call_receipt
+                .receipt
+                .execution_outcome
+                .outcome
+                .receipt_ids
+                .contains(resolve_receipt_id)
+            || find_function_call(transfer_call_receipt, "ft_transfer_call").is_none()
+        {
+            continue;
+        }
+        let logs = &transfer_call_receipt.receipt.execution_outcome.outcome.logs;
+        for (log_index, log) in logs.iter().enumerate() {
+            let Ok(transfer_log) = EventLogData::<FtTransferLog>::deserialize(log) else {
+                continue;
+            };
+            if !transfer_log.validate() {
+                continue;
+            }
+            for (event_index, transfer) in transfer_log.data.0.iter().enumerate() {
+                if transfer.old_owner_id == args.sender_id
+                    && transfer.new_owner_id == args.receiver_id
+                    && transfer.amount == args.amount
+                {
+                    return Some(log_event_id(
+                        &transfer_call_receipt.receipt.receipt.receipt_id,
+                        log_index,
+                        event_index,
+                    ));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Why tokens were sent with `ft_transfer_call`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TransferCallContext {
+    pub receiver_id: AccountId,
+    pub msg: String,
+    pub memo: Option<String>,
+    /// Method that the token calls on the receiver, which NEP-141 requires to
+    /// be `ft_on_transfer`
+    pub receiver_method: String,
+}
+
+#[derive(Deserialize)]
+struct TransferCallArgs {
+    receiver_id: AccountId,
+    msg: String,
+    memo: Option<String>,
+}
+
+/// Name of the first method called in the receipt
+pub(crate) fn get_method_name(receipt: &TransactionReceipt) -> Option<String> {
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return None;
+    };
+    actions.iter().find_map(|action| match action {
+        ActionView::FunctionCall { method_name, .. } => Some(method_name.clone()),
+        _ => None,
+    })
+}
+
+/// Arguments of `ft_transfer_call` if the receipt calls it. The call on the
+/// receiver is made in a later block, so its method is not taken from its
+/// receipt.
+pub(crate) fn get_transfer_call_context(
+    receipt: &TransactionReceipt,
+) -> Option<TransferCallContext> {
+    let args: TransferCallArgs =
+        serde_json::from_slice(find_function_call(receipt, "ft_transfer_call")?).ok()?;
+    Some(TransferCallContext {
+        receiver_id: args.receiver_id,
+        msg: args.msg,
+        memo: args.memo,
+        receiver_method: "ft_on_transfer".to_owned(),
+    })
+}
@@ -0,0 +1,116 @@
+//! Protection from contracts that log NEP-141 events for tokens they don't
+//! hold. Anyone can emit `EVENT_JSON:{"standard":"nep141",...}`, so a token
+//! event is only trusted if its contract is known to be a token.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use inindexer::near_indexer_primitives::views::StateChangeValueView;
+use inindexer::near_indexer_primitives::{CryptoHash, StreamerMessage};
+use inindexer::TransactionReceipt;
+
+use crate::transfer_call::get_method_name;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnverifiedTokenPolicy {
+    /// Pass the events to the handler with `token_verified` set to `false`
+    #[default]
+    Flag,
+    /// Don't pass the events to the handler
+    Drop,
+}
+
+/// A contract is verified if any of the configured sources knows it
+#[derive(Clone, Debug, Default)]
+pub struct TokenVerificationOptions {
+    pub policy: UnverifiedTokenPolicy,
+    /// Contracts that are known to be tokens, see [`load_token_registry`]
+    pub known_tokens: HashSet<AccountId>,
+    /// Code hashes of known FT implementations. Contracts are verified once
+    /// an update of their account with one of these code hashes is seen.
+    pub allowed_code_hashes: HashSet<CryptoHash>,
+    /// Trust contracts after a successful `ft_transfer` or `ft_transfer_call`
+    /// made to them by another account. This only proves that someone uses the
+    /// contract as a token, so it's weaker than the other sources.
+    pub trust_transfer_calls: bool,
+}
+
+/// Reads a token registry file: one contract id per line, empty lines and
+/// lines starting with `#` are ignored
+pub fn load_token_registry(path: impl AsRef<Path>) -> std::io::Result<HashSet<AccountId>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid account id {line} in token registry: {e}"),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Contracts verified from what the indexer has seen so far
+#[derive(Debug, Default)]
+pub(crate) struct TokenVerifier {
+    with_allowed_code: HashSet<AccountId>,
+    used_as_token: HashSet<AccountId>,
+}
+
+impl TokenVerifier {
+    pub fn is_verified(&self, options: &TokenVerificationOptions, contract_id: &AccountId) -> bool {
+        options.known_tokens.contains(contract_id)
+            || self.with_allowed_code.contains(contract_id)
+            || (options.trust_transfer_calls && self.used_as_token.contains(contract_id))
+    }
+
+    pub fn observe_block(&mut self, options: &TokenVerificationOptions, block: &StreamerMessage) {
+        if options.allowed_code_hashes.is_empty() {
+            return;
+        }
+        for change in block.shards.iter().flat_map(|shard| &shard.state_changes) {
+            match &change.value {
+                StateChangeValueView::AccountUpdate {
+                    account_id,
+                    account,
+                } => {
+                    if options.allowed_code_hashes.contains(&account.code_hash) {
+                        self.with_allowed_code.insert(account_id.clone());
+                    } else {
+                        // Redeployed with different code
+                        self.with_allowed_code.remove(account_id);
+                    }
+                }
+                StateChangeValueView::AccountDeletion { account_id } => {
+                    self.with_allowed_code.remove(account_id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Should be called after the events of the receipt are verified, so
+    /// that a contract can't verify itself in the same receipt
+    pub fn observe_successful_receipt(
+        &mut self,
+        options: &TokenVerificationOptions,
+        receipt: &TransactionReceipt,
+    ) {
+        if !options.trust_transfer_calls
+            || receipt.receipt.receipt.predecessor_id == receipt.receipt.receipt.receiver_id
+        {
+            return;
+        }
+        if matches!(
+            get_method_name(receipt).as_deref(),
+            Some("ft_transfer" | "ft_transfer_call")
+        ) {
+            self.used_as_token
+                .insert(receipt.receipt.receipt.receiver_id.clone());
+        }
+    }
+}
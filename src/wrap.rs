@@ -0,0 +1,119 @@
+//! Wrapping and unwrapping of NEAR with `near_deposit` and `near_withdraw`
+//! on the wNEAR contract
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use inindexer::near_indexer_primitives::views::{ActionView, ReceiptEnumView};
+use inindexer::{IncompleteTransaction, TransactionReceipt};
+use serde::{Deserialize, Serialize};
+
+use crate::serde_utils::dec_format;
+use crate::transfer_call::find_function_call;
+use crate::{EventContext, FtEvent, NativeTransferKind, ReceiptEvent};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum WrapKind {
+    /// NEAR deposited, wNEAR minted
+    Wrap,
+    /// wNEAR burned, NEAR withdrawn
+    Unwrap,
+}
+
+/// Links the native NEAR leg and the wNEAR leg of wrapping or unwrapping
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NearWrapEvent {
+    pub kind: WrapKind,
+    pub account_id: AccountId,
+    #[serde(with = "dec_format")]
+    pub amount: u128,
+    /// [`EventContext::event_id`] of the native NEAR transfer. For unwrapping,
+    /// the transfer is made in the next receipt.
+    pub near_event_id: Option<String>,
+    /// [`EventContext::event_id`] of the wNEAR mint or burn
+    pub wnear_event_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NearWithdrawArgs {
+    #[serde(with = "dec_format")]
+    amount: u128,
+}
+
+/// Recognizes `near_deposit` and `near_withdraw` in a successful receipt to
+/// the wNEAR contract, given the events that were found in it
+pub(crate) fn get_wrap_event(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    events: &[(ReceiptEvent, EventContext)],
+) -> Option<NearWrapEvent> {
+    let account_id = &receipt.receipt.receipt.predecessor_id;
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return None;
+    };
+
+    let deposit = actions
+        .iter()
+        .enumerate()
+        .find_map(|(action_index, action)| match action {
+            ActionView::FunctionCall {
+                method_name,
+                deposit,
+                ..
+            } if method_name == "near_deposit" && *deposit > 0 => Some((action_index, *deposit)),
+            _ => None,
+        });
+    if let Some((action_index, deposit)) = deposit {
+        let near_event_id = events
+            .iter()
+            .find(|(_, context)| {
+                context.native_transfer == Some(NativeTransferKind::FunctionCallDeposit)
+                    && context.action_index == Some(action_index)
+            })
+            .map(|(_, context)| context.event_id());
+        let mint = events.iter().find_map(|(event, context)| match event {
+            ReceiptEvent::Ft(FtEvent::Mint(mint))
+                if context.native_transfer.is_none() && &mint.owner_id == account_id =>
+            {
+                Some((mint.amount, context.event_id()))
+            }
+            _ => None,
+        });
+        return Some(NearWrapEvent {
+            kind: WrapKind::Wrap,
+            account_id: account_id.clone(),
+            amount: mint.as_ref().map_or(deposit, |(amount, _)| *amount),
+            near_event_id,
+            wnear_event_id: mint.map(|(_, event_id)| event_id),
+        });
+    }
+
+    let args: NearWithdrawArgs =
+        serde_json::from_slice(find_function_call(receipt, "near_withdraw")?).ok()?;
+    let burn = events.iter().find_map(|(event, context)| match event {
+        ReceiptEvent::Ft(FtEvent::Burn(burn))
+            if context.native_transfer.is_none() && &burn.owner_id == account_id =>
+        {
+            Some(context.event_id())
+        }
+        _ => None,
+    });
+    // The NEAR is sent in the first receipt created by `near_withdraw`. Its
+    // content may not be known yet, but the event id only depends on its id.
+    let outcome_receipt_ids = &receipt.receipt.execution_outcome.outcome.receipt_ids;
+    let transfer_receipt_id = outcome_receipt_ids
+        .iter()
+        .find(|receipt_id| {
+            transaction
+                .receipts
+                .get(receipt_id)
+                .and_then(|child| child.as_ref())
+                .is_some_and(|child| &child.receipt.receipt.receiver_id == account_id)
+        })
+        .or(outcome_receipt_ids.first());
+    Some(NearWrapEvent {
+        kind: WrapKind::Unwrap,
+        account_id: account_id.clone(),
+        amount: args.amount,
+        near_event_id: transfer_receipt_id.map(|receipt_id| format!("{receipt_id}-action0")),
+        wnear_event_id: burn,
+    })
+}